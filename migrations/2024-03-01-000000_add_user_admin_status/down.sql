@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX users_created_at_id_idx;
+
+ALTER TABLE users
+    DROP COLUMN password_reset_required,
+    DROP COLUMN disabled_at;
@@ -0,0 +1,7 @@
+-- Account status managed through the admin API
+ALTER TABLE users
+    ADD COLUMN disabled_at TIMESTAMP,
+    ADD COLUMN password_reset_required BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Supports keyset pagination of the admin user listing
+CREATE INDEX users_created_at_id_idx ON users (created_at, id);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE sessions;
+
+ALTER TABLE users
+    DROP COLUMN suspended_until,
+    DROP COLUMN disabled_reason;
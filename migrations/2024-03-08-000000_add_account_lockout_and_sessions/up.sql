@@ -0,0 +1,15 @@
+-- Reason and expiry of account lockouts
+ALTER TABLE users
+    ADD COLUMN disabled_reason TEXT,
+    ADD COLUMN suspended_until TIMESTAMP;
+
+-- Tokens issued at login, so they can be traced back to their user
+CREATE TABLE sessions (
+    id SERIAL NOT NULL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
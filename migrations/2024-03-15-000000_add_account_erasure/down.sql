@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE sessions
+    DROP COLUMN revoked_at;
+
+ALTER TABLE users
+    DROP COLUMN anonymized_at,
+    DROP COLUMN deleted_at;
@@ -0,0 +1,8 @@
+-- Soft delete and erasure of accounts
+ALTER TABLE users
+    ADD COLUMN deleted_at TIMESTAMP,
+    ADD COLUMN anonymized_at TIMESTAMP;
+
+-- Revocation of tokens issued at login
+ALTER TABLE sessions
+    ADD COLUMN revoked_at TIMESTAMP;
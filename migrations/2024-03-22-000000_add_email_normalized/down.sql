@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX users_email_normalized_key;
+
+ALTER TABLE users
+    DROP COLUMN email_normalized;
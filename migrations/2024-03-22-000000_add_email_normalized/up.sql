@@ -0,0 +1,8 @@
+-- Case-insensitive identity of an account's email address
+ALTER TABLE users
+    ADD COLUMN email_normalized TEXT;
+
+-- Existing accounts are given their identity by the server at startup, which normalizes addresses the
+-- same way as logins do (IDNA domains, Unicode lowercasing). See `email::backfill`.
+
+CREATE UNIQUE INDEX users_email_normalized_key ON users (email_normalized);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE audit_event_clients;
+
+DROP TABLE audit_events;
+
+DROP FUNCTION audit_events_append_only();
@@ -0,0 +1,42 @@
+-- Tamper-evident log of security events. Each row's hash covers its contents and the hash of the
+-- previous row, so changing or removing a row breaks the chain from that row on.
+CREATE TABLE audit_events (
+    id BIGSERIAL PRIMARY KEY,
+    occurred_at TIMESTAMP NOT NULL,
+    event_type TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    actor TEXT,
+    -- Not a foreign key: events are kept after the account they concern is deleted.
+    target_user_id INTEGER,
+    request_id TEXT,
+    -- JSON kept as written, since it is covered by the hash.
+    details TEXT NOT NULL,
+    prev_hash TEXT NOT NULL,
+    hash TEXT NOT NULL UNIQUE
+);
+
+CREATE INDEX audit_events_target_user_id_idx ON audit_events (target_user_id, id);
+CREATE INDEX audit_events_event_type_idx ON audit_events (event_type, id);
+
+-- The client an event came from is personal data that is erased with the account, so it is kept outside
+-- the append-only, hashed table.
+CREATE TABLE audit_event_clients (
+    event_id BIGINT PRIMARY KEY REFERENCES audit_events (id),
+    ip_address TEXT,
+    user_agent TEXT
+);
+
+-- Rows can only be appended.
+CREATE FUNCTION audit_events_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_events_no_update_or_delete
+    BEFORE UPDATE OR DELETE ON audit_events
+    FOR EACH ROW EXECUTE FUNCTION audit_events_append_only();
+
+CREATE TRIGGER audit_events_no_truncate
+    BEFORE TRUNCATE ON audit_events
+    FOR EACH STATEMENT EXECUTE FUNCTION audit_events_append_only();
@@ -0,0 +1,18 @@
+-- Successful logins, with the device and approximate location they came from
+CREATE TABLE logins (
+    id SERIAL NOT NULL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    logged_in_at TIMESTAMP NOT NULL,
+    ip_address TEXT,
+    user_agent TEXT,
+    browser TEXT NOT NULL,
+    operating_system TEXT NOT NULL,
+    device_type TEXT NOT NULL,
+    country TEXT,
+    city TEXT,
+    -- Hash of the browser, operating system and device type, used to recognize known devices.
+    device_fingerprint TEXT NOT NULL
+);
+
+CREATE INDEX logins_user_id_idx ON logins (user_id, logged_in_at);
+CREATE INDEX logins_user_id_device_fingerprint_idx ON logins (user_id, device_fingerprint);
@@ -0,0 +1,3 @@
+ALTER TABLE sessions
+    DROP COLUMN login_id,
+    DROP COLUMN last_used_at;
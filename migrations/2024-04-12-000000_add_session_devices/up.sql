@@ -0,0 +1,4 @@
+-- Device each session was issued to, through the login that created it, and when it was last used
+ALTER TABLE sessions
+    ADD COLUMN login_id INTEGER REFERENCES logins (id) ON DELETE SET NULL,
+    ADD COLUMN last_used_at TIMESTAMP;
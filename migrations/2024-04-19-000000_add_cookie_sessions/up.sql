@@ -0,0 +1,3 @@
+-- Whether a session authenticates with a bearer token or, for browser clients, a session cookie
+ALTER TABLE sessions
+    ADD COLUMN kind TEXT NOT NULL DEFAULT 'bearer' CHECK (kind IN ('bearer', 'cookie'));
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users
+    DROP COLUMN password_reset_token_hash,
+    DROP COLUMN password_reset_expires_at;
@@ -0,0 +1,5 @@
+-- One-time token emailed to a user when an admin forces a password reset, stored as a SHA-256 hash.
+-- Users flagged for a reset before this migration have no token; force the reset again to send one.
+ALTER TABLE users
+    ADD COLUMN password_reset_token_hash TEXT,
+    ADD COLUMN password_reset_expires_at TIMESTAMP;
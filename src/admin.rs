@@ -0,0 +1,885 @@
+//! # Admin Handlers Module
+//!
+//! This module contains the request handlers for administrative user management. All routes are served
+//! under `/admin` and require a bearer token granting the admin scope (see `admin_validator` in main and
+//! `authorize`).
+
+use crate::audit::{self, AuditContext, Event, EventType};
+use crate::auth::{AuthenticatedUser, TokenClaims};
+use crate::bulk::{self, DataFormat};
+use crate::errors::ServiceError;
+use crate::models::{AuditEvent, User};
+use crate::password_policy::PasswordPolicy;
+use crate::password_resets::{self, PasswordResets};
+use crate::schema::users::dsl::*;
+use crate::sessions;
+use crate::validation;
+use crate::Pool;
+use actix_web::dev::ServiceRequest;
+use actix_web::{web, HttpMessage, HttpResponse, Result as ActixResult};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+use validator::Validate;
+
+// Page size of the user listing when none is requested, and the largest page allowed.
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+/// Checks that a request with a validated bearer token may use the admin routes.
+///
+/// The token must grant `admin_scope`. Tokens issued at login are rejected even then: login hands users
+/// tokens issued to this service's own Auth0 client (`client_id`), so if that client were granted the
+/// admin scope, every signed-in user would be an admin. A token is taken as issued at login when it
+/// belongs to a user session or when its subject or authorized party is the service's client.
+pub fn authorize(
+    req: &ServiceRequest, // Request whose token was validated
+    admin_scope: &str,    // Scope the token must grant
+    client_id: &str,      // Auth0 client of this service, used to issue tokens at login
+) -> Result<(), ServiceError> {
+    let extensions = req.extensions();
+    let Some(claims) = extensions.get::<TokenClaims>() else {
+        return Err(ServiceError::Unauthorized);
+    };
+    if !claims.has_scope(admin_scope) {
+        warn!(
+            "Token without the {} scope rejected for request: {:?}",
+            admin_scope,
+            req.path()
+        );
+        return Err(ServiceError::Forbidden);
+    }
+    if extensions.get::<AuthenticatedUser>().is_some() || claims.is_issued_to(client_id) {
+        warn!(
+            "Token issued at login rejected for admin request: {:?}",
+            req.path()
+        );
+        return Err(ServiceError::Forbidden);
+    }
+    Ok(())
+}
+
+/// A user as shown to admins, without the password hash.
+#[derive(Debug, Serialize)]
+pub struct AdminUser {
+    pub id: i32,
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub disabled_at: Option<chrono::NaiveDateTime>,
+    pub suspended_until: Option<chrono::NaiveDateTime>,
+    pub disabled_reason: Option<String>,
+    pub password_reset_required: bool,
+    pub deleted_at: Option<chrono::NaiveDateTime>,
+    pub anonymized_at: Option<chrono::NaiveDateTime>,
+}
+
+impl From<User> for AdminUser {
+    fn from(user: User) -> Self {
+        AdminUser {
+            id: user.id,
+            first_name: user.first_name,
+            last_name: user.last_name,
+            email: user.email,
+            created_at: user.created_at,
+            disabled_at: user.disabled_at,
+            suspended_until: user.suspended_until,
+            disabled_reason: user.disabled_reason,
+            password_reset_required: user.password_reset_required,
+            deleted_at: user.deleted_at,
+            anonymized_at: user.anonymized_at,
+        }
+    }
+}
+
+/// Sort direction of the user listing, by creation time.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Account status filter of the user listing.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatusFilter {
+    Active,
+    Disabled,
+    Suspended,
+    ResetRequired,
+    Deleted,
+}
+
+/// Request body of a disable action.
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct DisableRequest {
+    #[validate(length(max = 500))]
+    pub reason: Option<String>, // Why the account is disabled, for support staff.
+}
+
+/// Request body of a suspend action.
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct SuspendRequest {
+    pub until: chrono::NaiveDateTime, // End of the suspension.
+    #[validate(length(max = 500))]
+    pub reason: Option<String>, // Why the account is suspended, for support staff.
+}
+
+/// Query parameters of the user listing.
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    pub limit: Option<i64>,           // Page size, up to `MAX_PAGE_SIZE`.
+    pub cursor: Option<String>,       // `next_cursor` of the previous page.
+    pub search: Option<String>,       // Prefix of the email, first name or last name.
+    pub status: Option<StatusFilter>, // Only users with this account status.
+    #[serde(default)]
+    pub order: SortOrder, // Sort direction by `created_at`.
+}
+
+/// A page of the user listing.
+#[derive(Debug, Serialize)]
+pub struct UserPage {
+    pub users: Vec<AdminUser>,
+    pub next_cursor: Option<String>, // Pass as `cursor` to fetch the next page; absent on the last page.
+}
+
+// Encodes the position after `user` in the listing as an opaque cursor.
+fn encode_cursor(user: &AdminUser) -> String {
+    let position = format!(
+        "{}.{}",
+        user.created_at.and_utc().timestamp_micros(),
+        user.id
+    );
+    URL_SAFE_NO_PAD.encode(position)
+}
+
+// Decodes a cursor produced by `encode_cursor` into the creation time and id it points after.
+fn decode_cursor(cursor: &str) -> Result<(chrono::NaiveDateTime, i32), ServiceError> {
+    let invalid = || ServiceError::BadRequest("Invalid cursor".to_string());
+
+    let position = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?;
+    let position = String::from_utf8(position).map_err(|_| invalid())?;
+    let (micros, user_id) = position.split_once('.').ok_or_else(invalid)?;
+    let timestamp = chrono::DateTime::from_timestamp_micros(micros.parse().map_err(|_| invalid())?)
+        .ok_or_else(invalid)?;
+    Ok((
+        timestamp.naive_utc(),
+        user_id.parse().map_err(|_| invalid())?,
+    ))
+}
+
+// Escapes the LIKE wildcards in a search term so it only matches as a literal prefix.
+fn like_prefix(term: &str) -> String {
+    let mut pattern = String::with_capacity(term.len() + 1);
+    for c in term.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// Handler for listing users.
+///
+/// Users are returned in pages ordered by creation time (ties broken by id), optionally
+/// filtered by a case-insensitive email or name prefix and by account status. Pagination uses
+/// keyset cursors, so pages stay consistent while users are added or removed.
+///
+/// # Arguments
+///
+/// * `db`: Database connection pool.
+/// * `query`: Page size, cursor, search term, status filter and sort order.
+///
+/// # Returns
+///
+/// This function returns an Actix result with a page of users or a ServiceError.
+pub async fn list_users(
+    db: web::Data<Pool>,          // Database connection pool
+    query: web::Query<ListQuery>, // Listing options
+) -> ActixResult<HttpResponse, ServiceError> {
+    let query = query.into_inner();
+    let page_size = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let after = query.cursor.as_deref().map(decode_cursor).transpose()?;
+
+    let mut page: Vec<AdminUser> = web::block(move || {
+        let mut conn = db.get().map_err(ServiceError::Pool)?;
+        let mut select = users.into_boxed();
+
+        if let Some(term) = query
+            .search
+            .as_deref()
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+        {
+            let pattern = like_prefix(term);
+            select = select.filter(
+                email
+                    .ilike(pattern.clone())
+                    .or(first_name.ilike(pattern.clone()))
+                    .or(last_name.ilike(pattern)),
+            );
+        }
+
+        select = match query.status {
+            Some(StatusFilter::Active) => select
+                .filter(deleted_at.is_null())
+                .filter(disabled_at.is_null())
+                .filter(
+                    suspended_until
+                        .is_null()
+                        .or(suspended_until.le(chrono::Local::now().naive_local())),
+                ),
+            Some(StatusFilter::Disabled) => select.filter(disabled_at.is_not_null()),
+            Some(StatusFilter::Suspended) => {
+                select.filter(suspended_until.gt(chrono::Local::now().naive_local()))
+            }
+            Some(StatusFilter::ResetRequired) => select.filter(password_reset_required.eq(true)),
+            Some(StatusFilter::Deleted) => select.filter(deleted_at.is_not_null()),
+            None => select,
+        };
+
+        select = match (query.order, after) {
+            (SortOrder::Asc, Some((after_created_at, after_id))) => select.filter(
+                created_at
+                    .gt(after_created_at)
+                    .or(created_at.eq(after_created_at).and(id.gt(after_id))),
+            ),
+            (SortOrder::Desc, Some((after_created_at, after_id))) => select.filter(
+                created_at
+                    .lt(after_created_at)
+                    .or(created_at.eq(after_created_at).and(id.lt(after_id))),
+            ),
+            (_, None) => select,
+        };
+
+        select = match query.order {
+            SortOrder::Asc => select.order((created_at.asc(), id.asc())),
+            SortOrder::Desc => select.order((created_at.desc(), id.desc())),
+        };
+
+        // Fetch one extra row to learn whether another page follows.
+        select
+            .limit(page_size + 1)
+            .load::<User>(&mut conn)
+            .map_err(ServiceError::Diesel)
+    })
+    .await??
+    .into_iter()
+    .map(AdminUser::from)
+    .collect();
+
+    let next_cursor = if page.len() as i64 > page_size {
+        page.truncate(page_size as usize);
+        page.last().map(encode_cursor)
+    } else {
+        None
+    };
+
+    Ok(HttpResponse::Ok().json(UserPage {
+        users: page,
+        next_cursor,
+    }))
+}
+
+/// Handler for fetching a single user.
+///
+/// # Arguments
+///
+/// * `db`: Database connection pool.
+/// * `path`: The id of the user.
+///
+/// # Returns
+///
+/// This function returns an Actix result with the user or a ServiceError.
+pub async fn get_user(
+    db: web::Data<Pool>,  // Database connection pool
+    path: web::Path<i32>, // User id
+) -> ActixResult<HttpResponse, ServiceError> {
+    let user_id = path.into_inner();
+    let user = web::block(move || {
+        let mut conn = db.get().map_err(ServiceError::Pool)?;
+        users
+            .find(user_id)
+            .first::<User>(&mut conn)
+            .optional()
+            .map_err(ServiceError::Diesel)
+    })
+    .await??
+    .ok_or(ServiceError::NotFound)?;
+
+    Ok(HttpResponse::Ok().json(AdminUser::from(user)))
+}
+
+/// Handler for disabling a user until they are re-enabled.
+///
+/// A disabled user cannot log in, and tokens issued to them at login stop working. The optional
+/// request body records a reason. Disabling an already disabled user keeps the original timestamp.
+pub async fn disable_user(
+    claims: web::ReqData<TokenClaims>, // Claims of the admin's token
+    db: web::Data<Pool>,               // Database connection pool
+    path: web::Path<i32>,              // User id
+    body: web::Bytes,                  // Optional JSON body with a reason
+    context: AuditContext,             // Client details for the audit log
+) -> ActixResult<HttpResponse, ServiceError> {
+    let user_id = path.into_inner();
+    let reason = if body.is_empty() {
+        None
+    } else {
+        let request: DisableRequest =
+            serde_json::from_slice(&body).map_err(|e| validation::deserialize_error(&e))?;
+        request.validate()?;
+        request.reason
+    };
+    info!(
+        "{} is disabling user id {} (reason: {:?})",
+        claims.sub, user_id, reason
+    );
+
+    let mut event = Event::success(EventType::UserDisabled)
+        .by_admin(&claims.sub)
+        .target(user_id);
+    if let Some(reason) = &reason {
+        event = event.detail("reason", reason.as_str());
+    }
+
+    let user = update_user(db.clone(), user_id, move |conn| {
+        if reason.is_some() {
+            diesel::update(users.find(user_id))
+                .set(disabled_reason.eq(reason))
+                .execute(conn)?;
+        }
+        diesel::update(users.find(user_id).filter(disabled_at.is_null()))
+            .set(disabled_at.eq(chrono::Local::now().naive_local()))
+            .execute(conn)
+    })
+    .await?;
+    audit::record(db, &context, event).await;
+    Ok(HttpResponse::Ok().json(user))
+}
+
+/// Handler for suspending a user until a given time.
+///
+/// A suspended user cannot log in, and tokens issued to them at login stop working, until the
+/// suspension expires. Suspending again replaces the previous expiry.
+pub async fn suspend_user(
+    claims: web::ReqData<TokenClaims>, // Claims of the admin's token
+    db: web::Data<Pool>,               // Database connection pool
+    path: web::Path<i32>,              // User id
+    body: web::Json<SuspendRequest>,   // Expiry and optional reason
+    context: AuditContext,             // Client details for the audit log
+) -> ActixResult<HttpResponse, ServiceError> {
+    let user_id = path.into_inner();
+    body.validate()?;
+    let SuspendRequest { until, reason } = body.into_inner();
+    if until <= chrono::Local::now().naive_local() {
+        return Err(ServiceError::BadRequest(
+            "Invalid input: The suspension must end in the future".to_string(),
+        ));
+    }
+    info!(
+        "{} is suspending user id {} until {} (reason: {:?})",
+        claims.sub, user_id, until, reason
+    );
+
+    let mut event = Event::success(EventType::UserSuspended)
+        .by_admin(&claims.sub)
+        .target(user_id)
+        .detail("until", until.to_string());
+    if let Some(reason) = &reason {
+        event = event.detail("reason", reason.as_str());
+    }
+
+    let user = update_user(db.clone(), user_id, move |conn| {
+        diesel::update(users.find(user_id))
+            .set((suspended_until.eq(until), disabled_reason.eq(reason)))
+            .execute(conn)
+    })
+    .await?;
+    audit::record(db, &context, event).await;
+    Ok(HttpResponse::Ok().json(user))
+}
+
+/// Handler for re-enabling a disabled or suspended user.
+pub async fn enable_user(
+    claims: web::ReqData<TokenClaims>, // Claims of the admin's token
+    db: web::Data<Pool>,               // Database connection pool
+    path: web::Path<i32>,              // User id
+    context: AuditContext,             // Client details for the audit log
+) -> ActixResult<HttpResponse, ServiceError> {
+    let user_id = path.into_inner();
+    info!("{} is enabling user id {}", claims.sub, user_id);
+
+    let user = update_user(db.clone(), user_id, move |conn| {
+        diesel::update(users.find(user_id))
+            .set((
+                disabled_at.eq(None::<chrono::NaiveDateTime>),
+                suspended_until.eq(None::<chrono::NaiveDateTime>),
+                disabled_reason.eq(None::<String>),
+            ))
+            .execute(conn)
+    })
+    .await?;
+    let event = Event::success(EventType::UserEnabled)
+        .by_admin(&claims.sub)
+        .target(user_id);
+    audit::record(db, &context, event).await;
+    Ok(HttpResponse::Ok().json(user))
+}
+
+/// Handler for forcing a user to change their password.
+///
+/// The user's sessions are revoked in the same transaction, and they cannot log in until they have set a
+/// new password with the one-time token emailed to them. Requires an SMTP server to send the token.
+pub async fn force_password_reset(
+    claims: web::ReqData<TokenClaims>, // Claims of the admin's token
+    db: web::Data<Pool>,               // Database connection pool
+    resets: web::Data<PasswordResets>, // Sender of reset tokens
+    path: web::Path<i32>,              // User id
+    context: AuditContext,             // Client details for the audit log
+) -> ActixResult<HttpResponse, ServiceError> {
+    let user_id = path.into_inner();
+    info!(
+        "{} is forcing a password reset for user id {}",
+        claims.sub, user_id
+    );
+    if !resets.is_enabled() {
+        error!("Cannot force a password reset, SMTP_URL is not set");
+        return Err(ServiceError::EnvironmentError);
+    }
+
+    let (token, token_hash) = password_resets::generate_token();
+    let user = update_user(db.clone(), user_id, move |conn| {
+        let flagged = diesel::update(users.find(user_id))
+            .set((
+                password_reset_required.eq(true),
+                password_reset_token_hash.eq(token_hash),
+                password_reset_expires_at.eq(password_resets::token_expiry()),
+            ))
+            .execute(conn)?;
+        sessions::revoke_user_sessions(conn, user_id)?;
+        Ok(flagged)
+    })
+    .await?;
+    let event = Event::success(EventType::PasswordResetForced)
+        .by_admin(&claims.sub)
+        .target(user_id);
+    audit::record(db, &context, event).await;
+
+    // The reset is in place even if the email fails; forcing it again sends a new token.
+    let to = user.email.clone();
+    web::block(move || resets.send(&to, &token))
+        .await?
+        .map_err(|e| {
+            error!(
+                "Failed to send password reset email to user id {}: {}",
+                user_id, e
+            );
+            ServiceError::InternalServerError
+        })?;
+    Ok(HttpResponse::Ok().json(user))
+}
+
+/// Handler for restoring an account the user deleted, during its grace period.
+///
+/// Accounts that have already been anonymized cannot be restored. Sessions revoked by the deletion
+/// stay revoked; the user logs in again.
+pub async fn restore_user(
+    claims: web::ReqData<TokenClaims>, // Claims of the admin's token
+    db: web::Data<Pool>,               // Database connection pool
+    path: web::Path<i32>,              // User id
+    context: AuditContext,             // Client details for the audit log
+) -> ActixResult<HttpResponse, ServiceError> {
+    let user_id = path.into_inner();
+    info!("{} is restoring user id {}", claims.sub, user_id);
+
+    let user = update_user(db.clone(), user_id, move |conn| {
+        diesel::update(users.find(user_id).filter(anonymized_at.is_null()))
+            .set(deleted_at.eq(None::<chrono::NaiveDateTime>))
+            .execute(conn)
+    })
+    .await?;
+
+    if user.anonymized_at.is_some() {
+        return Err(ServiceError::BadRequest(
+            "This account has already been erased and cannot be restored".to_string(),
+        ));
+    }
+    let event = Event::success(EventType::UserRestored)
+        .by_admin(&claims.sub)
+        .target(user_id);
+    audit::record(db, &context, event).await;
+    Ok(HttpResponse::Ok().json(user))
+}
+
+/// Handler for deleting a user immediately, without a grace period.
+///
+/// Like the erasure of deleted accounts, this also erases the IP addresses and user agents recorded with the
+/// audit events concerning the user.
+pub async fn delete_user(
+    claims: web::ReqData<TokenClaims>, // Claims of the admin's token
+    db: web::Data<Pool>,               // Database connection pool
+    path: web::Path<i32>,              // User id
+    context: AuditContext,             // Client details for the audit log
+) -> ActixResult<HttpResponse, ServiceError> {
+    let user_id = path.into_inner();
+
+    let delete_pool = db.clone();
+    let deleted = web::block(move || {
+        let mut conn = delete_pool.get().map_err(ServiceError::Pool)?;
+        conn.transaction(|conn| {
+            // The audit events are kept, but not the clients they were recorded with.
+            audit::erase_clients(conn, user_id)?;
+            diesel::delete(users.find(user_id)).execute(conn)
+        })
+        .map_err(ServiceError::Diesel)
+    })
+    .await??;
+
+    if deleted == 0 {
+        return Err(ServiceError::NotFound);
+    }
+    warn!("{} deleted user id {}", claims.sub, user_id);
+    let event = Event::success(EventType::UserDeleted)
+        .by_admin(&claims.sub)
+        .target(user_id);
+    audit::record(db, &context, event).await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Applies an update to a single user and returns the user as it is afterwards.
+// The update may legitimately change no rows, so existence is checked by reloading the user.
+async fn update_user<F>(
+    pool: web::Data<Pool>,
+    user_id: i32,
+    update: F,
+) -> Result<AdminUser, ServiceError>
+where
+    F: FnOnce(&mut PgConnection) -> QueryResult<usize> + Send + 'static,
+{
+    let user = web::block(move || {
+        let mut conn = pool.get().map_err(ServiceError::Pool)?;
+        conn.transaction(|conn| {
+            update(conn)?;
+            users.find(user_id).first::<User>(conn).optional()
+        })
+        .map_err(ServiceError::Diesel)
+    })
+    .await??
+    .ok_or(ServiceError::NotFound)?;
+
+    Ok(AdminUser::from(user))
+}
+
+/// Query parameters of a user import.
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    pub format: DataFormat,
+    #[serde(default)]
+    pub dry_run: bool, // Validate only, without writing anything.
+}
+
+/// Query parameters of a user export.
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    pub format: Option<DataFormat>, // Defaults to JSON.
+}
+
+/// Handler for bulk user imports.
+///
+/// The request body holds the users as CSV (with a header row) or as a JSON array, matching the `format`
+/// query parameter. Rows are validated and imported individually.
+///
+/// # Arguments
+///
+/// * `claims`: Claims of the admin's token.
+/// * `db`: Database connection pool.
+/// * `policy`: Password policy applied to plaintext passwords.
+/// * `query`: Import format and dry-run flag.
+/// * `body`: Raw import data.
+/// * `context`: Client the request came from, for the audit log.
+///
+/// # Returns
+///
+/// This function returns an Actix result with the import report or a ServiceError.
+pub async fn import_users(
+    claims: web::ReqData<TokenClaims>, // Claims of the admin's token
+    db: web::Data<Pool>,               // Database connection pool
+    policy: web::Data<PasswordPolicy>, // Password policy
+    query: web::Query<ImportQuery>,    // Import options
+    body: web::Bytes,                  // Import data
+    context: AuditContext,             // Client details for the audit log
+) -> ActixResult<HttpResponse, ServiceError> {
+    info!(
+        "{} is importing users from {} bytes of {:?} data{}",
+        claims.sub,
+        body.len(),
+        query.format,
+        if query.dry_run { " (dry run)" } else { "" }
+    );
+
+    let records = bulk::parse_records(query.format, &body)?;
+    let report = bulk::import_users(db.clone(), policy, records, query.dry_run).await?;
+    if !report.dry_run {
+        let event = Event::success(EventType::UsersImported)
+            .by_admin(&claims.sub)
+            .detail("imported", report.imported)
+            .detail("failed", report.failed);
+        audit::record(db, &context, event).await;
+    }
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Handler for user exports.
+///
+/// Exports all users without their password hashes, as CSV or JSON.
+///
+/// # Arguments
+///
+/// * `claims`: Claims of the admin's token.
+/// * `db`: Database connection pool.
+/// * `query`: Export format.
+/// * `context`: Client the request came from, for the audit log.
+///
+/// # Returns
+///
+/// This function returns an Actix result with the exported users or a ServiceError.
+pub async fn export_users(
+    claims: web::ReqData<TokenClaims>, // Claims of the admin's token
+    db: web::Data<Pool>,               // Database connection pool
+    query: web::Query<ExportQuery>,    // Export options
+    context: AuditContext,             // Client details for the audit log
+) -> ActixResult<HttpResponse, ServiceError> {
+    let format = query.format.unwrap_or(DataFormat::Json);
+    info!("{} is exporting users as {:?}", claims.sub, format);
+    let data = bulk::export_users(db.clone(), format).await?;
+    let event = Event::success(EventType::UsersExported)
+        .by_admin(&claims.sub)
+        .detail("format", format.content_type());
+    audit::record(db, &context, event).await;
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .body(data))
+}
+
+/// Query parameters of the audit event listing.
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    pub limit: Option<i64>,                   // Page size, up to `MAX_PAGE_SIZE`.
+    pub cursor: Option<String>,               // `next_cursor` of the previous page.
+    pub event_type: Option<String>,           // Only events of this type, such as `login`.
+    pub target_user_id: Option<i32>,          // Only events concerning this user.
+    pub actor: Option<String>,                // Only events by this actor, such as `user:42`.
+    pub since: Option<chrono::NaiveDateTime>, // Only events at or after this time.
+    pub until: Option<chrono::NaiveDateTime>, // Only events before this time.
+}
+
+/// A page of the audit event listing.
+#[derive(Debug, Serialize)]
+pub struct AuditEventPage {
+    pub events: Vec<AuditEvent>,
+    pub next_cursor: Option<String>, // Pass as `cursor` to fetch the next page; absent on the last page.
+}
+
+// Encodes the position after an audit event in the listing as an opaque cursor.
+fn encode_event_cursor(event: &AuditEvent) -> String {
+    URL_SAFE_NO_PAD.encode(event.id.to_string())
+}
+
+// Decodes a cursor produced by `encode_event_cursor` into the id of the event it points after.
+fn decode_event_cursor(cursor: &str) -> Result<i64, ServiceError> {
+    let invalid = || ServiceError::BadRequest("Invalid cursor".to_string());
+
+    let position = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?;
+    String::from_utf8(position)
+        .map_err(|_| invalid())?
+        .parse()
+        .map_err(|_| invalid())
+}
+
+/// Handler for listing audit events.
+///
+/// Events are returned newest first, in pages, optionally filtered by event type, target user, actor
+/// and time range. Each event includes the hashes linking it into the chain.
+///
+/// # Arguments
+///
+/// * `db`: Database connection pool.
+/// * `query`: Page size, cursor and filters.
+///
+/// # Returns
+///
+/// This function returns an Actix result with a page of events or a ServiceError.
+pub async fn list_audit_events(
+    db: web::Data<Pool>,           // Database connection pool
+    query: web::Query<AuditQuery>, // Listing options
+) -> ActixResult<HttpResponse, ServiceError> {
+    let query = query.into_inner();
+    let page_size = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let filter = audit::EventFilter {
+        event_type: query.event_type,
+        target_user_id: query.target_user_id,
+        actor: query.actor,
+        since: query.since,
+        until: query.until,
+        before_id: query
+            .cursor
+            .as_deref()
+            .map(decode_event_cursor)
+            .transpose()?,
+    };
+
+    // Fetch one extra row to learn whether another page follows.
+    let mut events = web::block(move || {
+        let mut conn = db.get().map_err(ServiceError::Pool)?;
+        audit::find_events(&mut conn, filter, page_size + 1).map_err(ServiceError::Diesel)
+    })
+    .await??;
+
+    let next_cursor = if events.len() as i64 > page_size {
+        events.truncate(page_size as usize);
+        events.last().map(encode_event_cursor)
+    } else {
+        None
+    };
+
+    Ok(HttpResponse::Ok().json(AuditEventPage {
+        events,
+        next_cursor,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn rejects_tokens_issued_at_login() {
+        use crate::sessions::SessionKind;
+        use actix_web::middleware::{from_fn, Next};
+        use actix_web::test::{init_service, try_call_service, TestRequest};
+        use actix_web::{body::MessageBody, dev::ServiceResponse, App, Error};
+
+        // Stands in for the token validator, which adds the claims of a valid token and, for tokens
+        // issued at login, the user of the session.
+        async fn validated(
+            req: ServiceRequest,
+            next: Next<impl MessageBody>,
+        ) -> Result<ServiceResponse<impl MessageBody>, Error> {
+            let header = |name: &str| {
+                req.headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string)
+            };
+            let claims = TokenClaims {
+                sub: header("x-sub").unwrap(),
+                azp: header("x-azp"),
+                scopes: vec!["admin:users".to_string()],
+            };
+            req.extensions_mut().insert(claims);
+            if header("x-session").is_some() {
+                req.extensions_mut().insert(AuthenticatedUser {
+                    user_id: 1,
+                    session_id: 1,
+                    kind: SessionKind::Bearer,
+                });
+            }
+            authorize(&req, "admin:users", "service-client")?;
+            next.call(req).await
+        }
+
+        let app = init_service(
+            App::new().service(
+                web::scope("/admin")
+                    .wrap(from_fn(validated))
+                    .route("/users", web::get().to(HttpResponse::Ok)),
+            ),
+        )
+        .await;
+        // Status of the response to a request with the given headers, or of the error rejecting it.
+        let status = |headers: &[(&str, &str)]| {
+            let mut req = TestRequest::get().uri("/admin/users");
+            for header in headers {
+                req = req.insert_header(*header);
+            }
+            let res = try_call_service(&app, req.to_request());
+            async {
+                match res.await {
+                    Ok(res) => res.status(),
+                    Err(e) => e.error_response().status(),
+                }
+            }
+        };
+
+        // A login-issued token: the service's client credentials token, mapped to a user session.
+        let login_token = [
+            ("x-sub", "service-client@clients"),
+            ("x-azp", "service-client"),
+            ("x-session", "1"),
+        ];
+        assert_eq!(status(&login_token).await, 403);
+
+        // Tokens of the service's client are rejected without a recorded session too.
+        assert_eq!(status(&[("x-sub", "service-client@clients")]).await, 403);
+        let delegated = [("x-sub", "auth0|admin"), ("x-azp", "service-client")];
+        assert_eq!(status(&delegated).await, 403);
+
+        // Tokens issued to admins or to other clients pass.
+        let admin = [("x-sub", "auth0|admin"), ("x-azp", "admin-console")];
+        assert_eq!(status(&admin).await, 200);
+        assert_eq!(status(&[("x-sub", "ops-client@clients")]).await, 200);
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let user = AdminUser {
+            id: 42,
+            first_name: "Ada".to_string(),
+            last_name: "Lovelace".to_string(),
+            email: "ada@example.com".to_string(),
+            created_at: chrono::NaiveDate::from_ymd_opt(2024, 2, 4)
+                .unwrap()
+                .and_hms_micro_opt(8, 5, 16, 123456)
+                .unwrap(),
+            disabled_at: None,
+            suspended_until: None,
+            disabled_reason: None,
+            password_reset_required: false,
+            deleted_at: None,
+            anonymized_at: None,
+        };
+
+        let cursor = encode_cursor(&user);
+        assert_eq!(decode_cursor(&cursor).unwrap(), (user.created_at, 42));
+        assert!(decode_cursor("not a cursor").is_err());
+    }
+
+    #[test]
+    fn audit_event_cursor_round_trips() {
+        let cursor = URL_SAFE_NO_PAD.encode("1234");
+        assert_eq!(decode_event_cursor(&cursor).unwrap(), 1234);
+        assert!(decode_event_cursor(&URL_SAFE_NO_PAD.encode("12.5")).is_err());
+        assert!(decode_event_cursor("not a cursor").is_err());
+    }
+
+    #[test]
+    fn search_terms_match_literally() {
+        assert_eq!(like_prefix("ada"), "ada%");
+        assert_eq!(like_prefix("100%_\\"), "100\\%\\_\\\\%");
+    }
+}
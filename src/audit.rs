@@ -0,0 +1,617 @@
+//! # Audit Log Module
+//!
+//! This module records security events, such as logins, password changes and admin actions, in the
+//! append-only `audit_events` table. Each event stores who acted, which user it concerns and the request
+//! id. Every row carries a SHA-256 hash of its contents and of the previous row's hash, so editing, removing
+//! or reordering rows breaks the chain; `verify_chain` walks the table and reports the first event that does
+//! not match. The client's IP address and user agent are personal data that must be erasable with the
+//! account, so they are kept in `audit_event_clients`, outside the hash, and `erase_clients` removes them.
+
+use crate::client_ip;
+use crate::errors::ServiceError;
+use crate::models::{AuditEvent, NewAuditEvent, NewAuditEventClient};
+use crate::request_id::RequestId;
+use crate::schema::{audit_event_clients, audit_events};
+use crate::telemetry;
+use crate::Pool;
+use actix_web::dev::Payload;
+use actix_web::http::header;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+use diesel::prelude::*;
+use futures::future::{ready, Ready};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+/// `prev_hash` of the first event in the chain.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// Key of the advisory lock serializing appends, so that each event links to the one before it.
+const CHAIN_LOCK_KEY: i64 = 0x6175_6469_7400;
+
+// Number of events read at a time when verifying the chain.
+const VERIFY_BATCH_SIZE: i64 = 1000;
+
+// Longest user agent stored with an event.
+const MAX_USER_AGENT_LEN: usize = 512;
+
+/// Kinds of security events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventType {
+    Signup,
+    Login,
+    PasswordChange,
+    AccountDeletion,
+    TokenRevocation,
+    UserDisabled,
+    UserSuspended,
+    UserEnabled,
+    PasswordResetForced,
+    UserRestored,
+    UserDeleted,
+    UsersImported,
+    UsersExported,
+}
+
+impl EventType {
+    /// Name under which the event is stored.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventType::Signup => "signup",
+            EventType::Login => "login",
+            EventType::PasswordChange => "password_change",
+            EventType::AccountDeletion => "account_deletion",
+            EventType::TokenRevocation => "token_revocation",
+            EventType::UserDisabled => "user_disabled",
+            EventType::UserSuspended => "user_suspended",
+            EventType::UserEnabled => "user_enabled",
+            EventType::PasswordResetForced => "password_reset_forced",
+            EventType::UserRestored => "user_restored",
+            EventType::UserDeleted => "user_deleted",
+            EventType::UsersImported => "users_imported",
+            EventType::UsersExported => "users_exported",
+        }
+    }
+}
+
+/// A security event to be recorded.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub event_type: EventType,
+    pub succeeded: bool,
+    pub actor: Option<String>, // `user:<id>` or `admin:<token subject>`.
+    pub target_user_id: Option<i32>, // User the event concerns.
+    pub details: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Event {
+    /// A successful event.
+    pub fn success(event_type: EventType) -> Self {
+        Event {
+            event_type,
+            succeeded: true,
+            actor: None,
+            target_user_id: None,
+            details: serde_json::Map::new(),
+        }
+    }
+
+    /// A failed attempt, such as a login with a wrong password.
+    pub fn failure(event_type: EventType) -> Self {
+        Event {
+            succeeded: false,
+            ..Event::success(event_type)
+        }
+    }
+
+    /// Attributes the event to a user acting on their own account.
+    pub fn by_user(mut self, user_id: i32) -> Self {
+        self.actor = Some(format!("user:{}", user_id));
+        self
+    }
+
+    /// Attributes the event to an admin, identified by the subject of their token.
+    pub fn by_admin(mut self, subject: &str) -> Self {
+        self.actor = Some(format!("admin:{}", subject));
+        self
+    }
+
+    /// Sets the user the event concerns.
+    pub fn target(mut self, user_id: i32) -> Self {
+        self.target_user_id = Some(user_id);
+        self
+    }
+
+    /// Adds a detail, such as the reason a login failed.
+    pub fn detail(mut self, key: &str, value: impl Into<serde_json::Value>) -> Self {
+        self.details.insert(key.to_string(), value.into());
+        self
+    }
+}
+
+/// The client a request came from, recorded with the events it causes.
+#[derive(Debug, Clone, Default)]
+pub struct AuditContext {
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+}
+
+impl FromRequest for AuditContext {
+    type Error = ServiceError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let ip_address = client_ip::client_ip(req);
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|agent| truncate(agent, MAX_USER_AGENT_LEN).to_string());
+        let request_id = req
+            .extensions()
+            .get::<RequestId>()
+            .map(|id| id.as_str().to_string());
+
+        ready(Ok(AuditContext {
+            ip_address,
+            user_agent,
+            request_id,
+        }))
+    }
+}
+
+// Cuts a string to at most `max` bytes, on a character boundary.
+fn truncate(text: &str, max: usize) -> &str {
+    let mut end = text.len().min(max);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Records an event, logging failures instead of failing the request that caused it.
+pub async fn record(pool: web::Data<Pool>, context: &AuditContext, event: Event) {
+    let context = context.clone();
+    let event_type = event.event_type;
+    let result = telemetry::db_block("record_audit_event", move || {
+        let mut conn = pool.get().map_err(ServiceError::Pool)?;
+        append(&mut conn, &context, event).map_err(ServiceError::Diesel)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => error!(
+            "Failed to record {} audit event: {:?}",
+            event_type.as_str(),
+            e
+        ),
+        Err(e) => error!(
+            "Failed to record {} audit event: {:?}",
+            event_type.as_str(),
+            e
+        ),
+    }
+}
+
+/// Appends an event to the chain and returns it as stored.
+pub fn append(
+    conn: &mut PgConnection,
+    context: &AuditContext,
+    event: Event,
+) -> QueryResult<AuditEvent> {
+    conn.transaction(|conn| {
+        diesel::sql_query("SELECT pg_advisory_xact_lock($1)")
+            .bind::<diesel::sql_types::BigInt, _>(CHAIN_LOCK_KEY)
+            .execute(conn)?;
+
+        let prev_hash = audit_events::table
+            .order(audit_events::id.desc())
+            .select(audit_events::hash)
+            .first::<String>(conn)
+            .optional()?
+            .unwrap_or_else(|| GENESIS_HASH.to_string());
+
+        // Postgres keeps microseconds, so the hash is computed over the time as it will be read back.
+        let now = chrono::Local::now().naive_local();
+        let occurred_at = chrono::DateTime::from_timestamp_micros(now.and_utc().timestamp_micros())
+            .map_or(now, |time| time.naive_utc());
+
+        let new_event = event.into_row(context, occurred_at, prev_hash);
+        let event_id = diesel::insert_into(audit_events::table)
+            .values(&new_event)
+            .returning(audit_events::id)
+            .get_result::<i64>(conn)?;
+        if context.ip_address.is_some() || context.user_agent.is_some() {
+            diesel::insert_into(audit_event_clients::table)
+                .values(&NewAuditEventClient {
+                    event_id,
+                    ip_address: context.ip_address.clone(),
+                    user_agent: context.user_agent.clone(),
+                })
+                .execute(conn)?;
+        }
+        Ok(new_event.stored(event_id, context))
+    })
+}
+
+/// Erases the clients recorded with the events a user caused or that concern them, returning how many
+/// were erased. The events themselves, and the chain, are kept.
+pub fn erase_clients(conn: &mut PgConnection, user_id: i32) -> QueryResult<usize> {
+    let events = audit_events::table
+        .filter(
+            audit_events::target_user_id
+                .eq(user_id)
+                .or(audit_events::actor.eq(format!("user:{}", user_id))),
+        )
+        .select(audit_events::id);
+    diesel::delete(audit_event_clients::table.filter(audit_event_clients::event_id.eq_any(events)))
+        .execute(conn)
+}
+
+impl Event {
+    // Builds the row for the event, linked to the event before it.
+    fn into_row(
+        self,
+        context: &AuditContext,
+        occurred_at: chrono::NaiveDateTime,
+        prev_hash: String,
+    ) -> NewAuditEvent {
+        let outcome = if self.succeeded { "success" } else { "failure" };
+        let mut row = NewAuditEvent {
+            occurred_at,
+            event_type: self.event_type.as_str().to_string(),
+            outcome: outcome.to_string(),
+            actor: self.actor,
+            target_user_id: self.target_user_id,
+            request_id: context.request_id.clone(),
+            details: serde_json::Value::Object(self.details).to_string(),
+            prev_hash,
+            hash: String::new(),
+        };
+        row.hash = row.hashed_fields().hash();
+        row
+    }
+}
+
+// The fields covered by an event's hash, serialized as a JSON array in this order.
+#[derive(Serialize)]
+struct HashedFields<'a>(
+    &'a str,         // prev_hash
+    String,          // occurred_at, with microseconds
+    &'a str,         // event_type
+    &'a str,         // outcome
+    Option<&'a str>, // actor
+    Option<i32>,     // target_user_id
+    Option<&'a str>, // request_id
+    &'a str,         // details
+);
+
+impl HashedFields<'_> {
+    fn hash(&self) -> String {
+        let encoded = serde_json::to_vec(self).expect("hashed fields always serialize");
+        hex::encode(Sha256::digest(&encoded))
+    }
+}
+
+macro_rules! hashed_fields {
+    ($event:expr) => {
+        HashedFields(
+            &$event.prev_hash,
+            $event
+                .occurred_at
+                .format("%Y-%m-%dT%H:%M:%S%.6f")
+                .to_string(),
+            &$event.event_type,
+            &$event.outcome,
+            $event.actor.as_deref(),
+            $event.target_user_id,
+            $event.request_id.as_deref(),
+            &$event.details,
+        )
+    };
+}
+
+impl NewAuditEvent {
+    fn hashed_fields(&self) -> HashedFields<'_> {
+        hashed_fields!(self)
+    }
+
+    // The event as stored under `id`, with the client it came from.
+    fn stored(self, id: i64, context: &AuditContext) -> AuditEvent {
+        AuditEvent {
+            id,
+            occurred_at: self.occurred_at,
+            event_type: self.event_type,
+            outcome: self.outcome,
+            actor: self.actor,
+            target_user_id: self.target_user_id,
+            ip_address: context.ip_address.clone(),
+            user_agent: context.user_agent.clone(),
+            request_id: self.request_id,
+            details: self.details,
+            prev_hash: self.prev_hash,
+            hash: self.hash,
+        }
+    }
+}
+
+// Columns of a stored event, in the order of `AuditEvent`.
+type EventColumns = (
+    audit_events::id,
+    audit_events::occurred_at,
+    audit_events::event_type,
+    audit_events::outcome,
+    audit_events::actor,
+    audit_events::target_user_id,
+    diesel::dsl::Nullable<audit_event_clients::ip_address>,
+    diesel::dsl::Nullable<audit_event_clients::user_agent>,
+    audit_events::request_id,
+    audit_events::details,
+    audit_events::prev_hash,
+    audit_events::hash,
+);
+
+type StoredEvents = diesel::dsl::IntoBoxed<
+    'static,
+    diesel::dsl::Select<
+        diesel::dsl::LeftJoin<audit_events::table, audit_event_clients::table>,
+        EventColumns,
+    >,
+    diesel::pg::Pg,
+>;
+
+// Selects events along with the client each came from, unless it was erased.
+fn stored_events() -> StoredEvents {
+    audit_events::table
+        .left_join(audit_event_clients::table)
+        .select((
+            audit_events::id,
+            audit_events::occurred_at,
+            audit_events::event_type,
+            audit_events::outcome,
+            audit_events::actor,
+            audit_events::target_user_id,
+            audit_event_clients::ip_address.nullable(),
+            audit_event_clients::user_agent.nullable(),
+            audit_events::request_id,
+            audit_events::details,
+            audit_events::prev_hash,
+            audit_events::hash,
+        ))
+        .into_boxed()
+}
+
+impl AuditEvent {
+    fn hashed_fields(&self) -> HashedFields<'_> {
+        hashed_fields!(self)
+    }
+}
+
+/// Filters of the audit event listing.
+#[derive(Debug, Default)]
+pub struct EventFilter {
+    pub event_type: Option<String>,
+    pub target_user_id: Option<i32>,
+    pub actor: Option<String>,
+    pub since: Option<chrono::NaiveDateTime>, // Inclusive.
+    pub until: Option<chrono::NaiveDateTime>, // Exclusive.
+    pub before_id: Option<i64>,               // Only events older than this one.
+}
+
+/// Loads up to `limit` events matching a filter, newest first.
+pub fn find_events(
+    conn: &mut PgConnection,
+    filter: EventFilter,
+    limit: i64,
+) -> QueryResult<Vec<AuditEvent>> {
+    let mut select = stored_events();
+    if let Some(event_type) = filter.event_type {
+        select = select.filter(audit_events::event_type.eq(event_type));
+    }
+    if let Some(target_user_id) = filter.target_user_id {
+        select = select.filter(audit_events::target_user_id.eq(target_user_id));
+    }
+    if let Some(actor) = filter.actor {
+        select = select.filter(audit_events::actor.eq(actor));
+    }
+    if let Some(since) = filter.since {
+        select = select.filter(audit_events::occurred_at.ge(since));
+    }
+    if let Some(until) = filter.until {
+        select = select.filter(audit_events::occurred_at.lt(until));
+    }
+    if let Some(before_id) = filter.before_id {
+        select = select.filter(audit_events::id.lt(before_id));
+    }
+    select
+        .order(audit_events::id.desc())
+        .limit(limit)
+        .load::<AuditEvent>(conn)
+}
+
+/// An event that does not match the chain.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct ChainBreak {
+    pub id: i64,
+    pub problem: &'static str,
+}
+
+/// Result of verifying the chain.
+///
+/// Removing the newest events leaves a valid, shorter chain; comparing `events` and `head_hash` with the
+/// values from an earlier run detects this.
+#[derive(Debug, Serialize)]
+pub struct VerificationReport {
+    pub events: u64,                     // Events checked.
+    pub head_hash: String,               // Hash of the newest event, or the genesis hash.
+    pub first_break: Option<ChainBreak>, // First event that does not match, if any.
+}
+
+/// Walks the chain from the first event and reports the first event that was altered, removed or reordered.
+pub fn verify_chain(conn: &mut PgConnection) -> QueryResult<VerificationReport> {
+    let mut chain = ChainVerifier::new();
+    let mut after_id = 0;
+    loop {
+        let batch = stored_events()
+            .filter(audit_events::id.gt(after_id))
+            .order(audit_events::id.asc())
+            .limit(VERIFY_BATCH_SIZE)
+            .load::<AuditEvent>(conn)?;
+        let Some(last) = batch.last() else {
+            break;
+        };
+        after_id = last.id;
+
+        for event in &batch {
+            if !chain.check(event) {
+                return Ok(chain.report());
+            }
+        }
+    }
+    Ok(chain.report())
+}
+
+// Checks events one at a time, in chain order.
+struct ChainVerifier {
+    events: u64,
+    head_hash: String,
+    first_break: Option<ChainBreak>,
+}
+
+impl ChainVerifier {
+    fn new() -> Self {
+        ChainVerifier {
+            events: 0,
+            head_hash: GENESIS_HASH.to_string(),
+            first_break: None,
+        }
+    }
+
+    // Checks the next event, returning false once the chain is broken.
+    fn check(&mut self, event: &AuditEvent) -> bool {
+        let problem = if event.prev_hash != self.head_hash {
+            Some("does not link to the previous event; events before it were removed or reordered")
+        } else if event.hashed_fields().hash() != event.hash {
+            Some("contents do not match its hash")
+        } else {
+            None
+        };
+        if let Some(problem) = problem {
+            self.first_break = Some(ChainBreak {
+                id: event.id,
+                problem,
+            });
+            return false;
+        }
+        self.events += 1;
+        self.head_hash = event.hash.clone();
+        true
+    }
+
+    fn report(self) -> VerificationReport {
+        VerificationReport {
+            events: self.events,
+            head_hash: self.head_hash,
+            first_break: self.first_break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a stored event linked to `prev_hash`, as `append` would.
+    fn stored(id: i64, prev_hash: &str, event: Event) -> AuditEvent {
+        let context = AuditContext {
+            ip_address: Some("203.0.113.7".to_string()),
+            user_agent: Some("curl/8.5.0".to_string()),
+            request_id: None,
+        };
+        let occurred_at = chrono::NaiveDate::from_ymd_opt(2024, 3, 29)
+            .unwrap()
+            .and_hms_micro_opt(9, 30, 0, id as u32)
+            .unwrap();
+        event
+            .into_row(&context, occurred_at, prev_hash.to_string())
+            .stored(id, &context)
+    }
+
+    fn chain() -> Vec<AuditEvent> {
+        let signup = stored(1, GENESIS_HASH, Event::success(EventType::Signup).target(7));
+        let login = stored(
+            2,
+            &signup.hash,
+            Event::failure(EventType::Login)
+                .by_user(7)
+                .target(7)
+                .detail("reason", "invalid_credentials"),
+        );
+        let disable = stored(
+            3,
+            &login.hash,
+            Event::success(EventType::UserDisabled)
+                .by_admin("auth0|support")
+                .target(7),
+        );
+        vec![signup, login, disable]
+    }
+
+    fn verify(events: &[AuditEvent]) -> VerificationReport {
+        let mut verifier = ChainVerifier::new();
+        for event in events {
+            if !verifier.check(event) {
+                break;
+            }
+        }
+        verifier.report()
+    }
+
+    #[test]
+    fn accepts_an_intact_chain() {
+        let events = chain();
+        let report = verify(&events);
+
+        assert_eq!(report.events, 3);
+        assert_eq!(report.head_hash, events[2].hash);
+        assert_eq!(report.first_break, None);
+        assert_eq!(verify(&[]).head_hash, GENESIS_HASH);
+    }
+
+    #[test]
+    fn detects_altered_removed_and_reordered_events() {
+        let mut altered = chain();
+        altered[1].outcome = "success".to_string();
+        assert_eq!(verify(&altered).first_break.unwrap().id, 2);
+
+        let mut removed = chain();
+        removed.remove(1);
+        let report = verify(&removed);
+        assert_eq!(report.events, 1);
+        assert_eq!(report.first_break.unwrap().id, 3);
+
+        let mut reordered = chain();
+        reordered.swap(0, 1);
+        assert_eq!(verify(&reordered).first_break.unwrap().id, 2);
+    }
+
+    #[test]
+    fn erased_clients_leave_the_chain_intact() {
+        let mut erased = chain();
+        for event in &mut erased {
+            event.ip_address = None;
+            event.user_agent = None;
+        }
+        assert_eq!(verify(&erased).first_break, None);
+        assert_eq!(verify(&erased).head_hash, chain()[2].hash);
+    }
+
+    #[test]
+    fn truncates_user_agents_on_character_boundaries() {
+        assert_eq!(truncate("curl/8.5.0", 4), "curl");
+        assert_eq!(truncate("é", 1), "");
+        assert_eq!(truncate("short", MAX_USER_AGENT_LEN), "short");
+    }
+}
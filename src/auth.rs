@@ -8,18 +8,89 @@
 
 // Import relevant crates and modules for handling JWTs, serialization, and environment variables
 use crate::errors::ServiceError;
+use crate::metrics;
+use crate::sessions::SessionKind;
+use crate::settings::Auth0Settings;
+use crate::telemetry;
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
 use alcoholic_jwt::{token_kid, validate, Validation, JWKS};
-use log::{debug, error, info, warn};
+use futures::future::{ready, Ready};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::env;
 use std::error::Error;
+use std::time::Instant;
+use tracing::{debug, error, info, warn};
 
-// Claims struct used for deserializing JWT claims
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    sub: String,
-    company: String,
-    exp: usize,
+// Claims of a validated JWT that the application relies on.
+// Inserted into the request extensions by the token validator.
+#[derive(Debug, Clone)]
+pub struct TokenClaims {
+    pub sub: String,         // Subject the token was issued to.
+    pub azp: Option<String>, // Authorized party: the client the token was issued to, if stated.
+    pub scopes: Vec<String>, // Granted scopes, from the `scope` claim and Auth0's `permissions` claim.
+}
+
+impl TokenClaims {
+    // Extracts the relevant claims from a validated token's claim set.
+    fn from_json(claims: &serde_json::Value) -> Self {
+        let sub = claims["sub"].as_str().unwrap_or_default().to_string();
+        let azp = claims["azp"].as_str().map(str::to_string);
+
+        let mut scopes: Vec<String> = claims["scope"]
+            .as_str()
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+        if let Some(permissions) = claims["permissions"].as_array() {
+            scopes.extend(
+                permissions
+                    .iter()
+                    .filter_map(|p| p.as_str())
+                    .map(str::to_string),
+            );
+        }
+
+        TokenClaims { sub, azp, scopes }
+    }
+
+    // Returns true if the token grants the given scope.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+
+    // Returns true if the token was issued to the given Auth0 client through the client credentials
+    // flow, whose tokens have the subject `<client id>@clients`.
+    pub fn is_issued_to(&self, client_id: &str) -> bool {
+        self.azp.as_deref() == Some(client_id)
+            || self.sub == client_id
+            || self.sub.strip_suffix("@clients") == Some(client_id)
+    }
+}
+
+// The user a request's token was issued to at login.
+// Inserted into the request extensions by the token validator; handlers extract it to act on
+// behalf of the signed-in user. Requests with tokens not issued through login are rejected.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthenticatedUser {
+    pub user_id: i32,      // Id of the signed-in user.
+    pub session_id: i32,   // Id of the session the request's token belongs to.
+    pub kind: SessionKind, // Whether the session uses a bearer token or a session cookie.
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = ServiceError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<AuthenticatedUser>()
+                .copied()
+                .ok_or(ServiceError::Unauthorized),
+        )
+    }
 }
 
 // Represents the request payload for obtaining a token from Auth0
@@ -34,37 +105,33 @@ pub struct Auth0TokenRequest {
 // Represents the response from Auth0 upon successful token request
 #[derive(Serialize, Deserialize)]
 pub struct Auth0TokenResponse {
-    access_token: String,
+    pub access_token: String,
     token_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<i64>, // Lifetime of the access token in seconds.
 }
 
 // Asynchronously requests a JWT token from Auth0
-pub async fn request_auth0_token() -> Result<Auth0TokenResponse, Box<dyn std::error::Error>> {
+pub async fn request_auth0_token(
+    auth0: &Auth0Settings,
+) -> Result<Auth0TokenResponse, Box<dyn std::error::Error>> {
     // Logging the attempt to request a token
-    info!(
-        "Requesting Auth0 token for audience: {}",
-        env::var("AUTH0_AUDIENCE").unwrap_or_default()
-    );
+    info!("Requesting Auth0 token for audience: {}", auth0.audience);
 
     let client = reqwest::Client::new();
-
-    // Environment variables should be used to avoid hardcoding sensitive information
-    let client_id = env::var("AUTH0_CLIENT_ID")?;
-    let client_secret = env::var("AUTH0_CLIENT_SECRET")?;
-    let audience = env::var("AUTH0_AUDIENCE")?; // The identifier of your API in Auth0
-    let auth0_domain = env::var("AUTH0_DOMAIN")?;
-    let auth0_url = format!("https://{}/oauth/token", auth0_domain);
+    let auth0_url = format!("https://{}/oauth/token", auth0.domain);
 
     // Prepare the request payload
     let token_request = Auth0TokenRequest {
-        client_id,
-        client_secret,
-        audience,
+        client_id: auth0.client_id.clone(),
+        client_secret: auth0.client_secret.clone(),
+        audience: auth0.audience.clone(),
         grant_type: "client_credentials".to_string(),
     };
 
     // Send the request to Auth0 and await the response
-    let response = client.post(&auth0_url).json(&token_request).send().await?;
+    let request = client.post(&auth0_url).json(&token_request).build()?;
+    let response = telemetry::send(&client, request).await?;
 
     match response.json::<Auth0TokenResponse>().await {
         Ok(token_response) => {
@@ -79,38 +146,36 @@ pub async fn request_auth0_token() -> Result<Auth0TokenResponse, Box<dyn std::er
     }
 }
 
-
-// Validates a JWT token using JWKS from a specified authority
-pub async fn validate_token(token: &str) -> Result<bool, ServiceError> {
+// Validates a JWT token using JWKS from a specified authority and returns its claims
+pub async fn validate_token(token: &str, authority: &str) -> Result<TokenClaims, ServiceError> {
     debug!("Validating JWT token");
 
-    let authority = env::var("AUTHORITY").map_err(|_| ServiceError::EnvironmentError)?;
-    let jwks_uri = format!("{}{}", authority, ".well-known/jwks.json");
-
     // Fetch the JSON Web Key Set (JWKS) from the authority
-    let jwks = fetch_jwks(&jwks_uri).await.map_err(|e| {
+    let jwks: JWKS = fetch_jwks(&jwks_uri(authority)).await.map_err(|e| {
         error!("Error fetching JWKS: {:?}", e);
         ServiceError::JWKSFetchError
     })?;
 
     // Prepare validation criteria
-    let validations = vec![Validation::Issuer(authority), Validation::SubjectPresent];
-    let kid = match token_kid(&token) {
-        Ok(res) => res.expect("failed to decode kid"),
-        Err(_) => return Err(ServiceError::JWKSFetchError),
+    let validations = vec![
+        Validation::Issuer(authority.to_string()),
+        Validation::SubjectPresent,
+    ];
+    // Malformed tokens and tokens without a key id are invalid, not a JWKS failure
+    let kid = match token_kid(token) {
+        Ok(Some(kid)) => kid,
+        Ok(None) | Err(_) => return Err(ServiceError::TokenValidationError),
     };
 
     // Find the corresponding JWK in the JWKS for the token's KID
     let jwk = jwks.find(&kid).ok_or(ServiceError::JWKSFetchError)?;
-    let res = validate(token, jwk, validations)
-        .map(|_| true)
-        .map_err(|_| ServiceError::TokenValidationError);
+    let res = validate(token, jwk, validations).map_err(|_| ServiceError::TokenValidationError);
 
-    // Return true if token is valid, false otherwise
+    // Return the token's claims if it is valid
     match res {
-        Ok(_) => {
+        Ok(valid_jwt) => {
             info!("JWT token validated successfully");
-            Ok(true)
+            Ok(TokenClaims::from_json(&valid_jwt.claims))
         }
         Err(e) => {
             warn!("JWT token validation failed: {:?}", e);
@@ -119,10 +184,61 @@ pub async fn validate_token(token: &str) -> Result<bool, ServiceError> {
     }
 }
 
-// Asynchronously fetches JWKS from a specified URI
-async fn fetch_jwks(uri: &str) -> Result<JWKS, Box<dyn Error>> {
+// A key published in a JWKS, as far as needed to tell whether it verifies signatures.
+#[derive(Deserialize)]
+struct PublishedKey {
+    kid: Option<String>,
+    #[serde(rename = "use")]
+    key_use: Option<String>, // `sig` for signing keys; keys without it may be used for anything.
+}
+
+// The keys published in a JWKS.
+#[derive(Deserialize)]
+struct PublishedKeys {
+    keys: Vec<PublishedKey>,
+}
+
+// Returns the URI of the JWKS published by an authority
+fn jwks_uri(authority: &str) -> String {
+    format!("{}{}", authority, ".well-known/jwks.json")
+}
+
+// Fetches the authority's JWKS and returns the ids of the keys that verify token signatures
+pub async fn jwks_signing_keys(authority: &str) -> Result<Vec<String>, ServiceError> {
+    let document: serde_json::Value = fetch_jwks(&jwks_uri(authority))
+        .await
+        .map_err(|_| ServiceError::JWKSFetchError)?;
+
+    // The set must be usable for token validation, not just valid JSON
+    serde_json::from_value::<JWKS>(document.clone()).map_err(|e| {
+        error!("JWKS cannot be used for token validation: {:?}", e);
+        ServiceError::JWKSFetchError
+    })?;
+    let published: PublishedKeys =
+        serde_json::from_value(document).map_err(|_| ServiceError::JWKSFetchError)?;
+
+    Ok(published
+        .keys
+        .into_iter()
+        .filter(|key| key.key_use.as_deref().unwrap_or("sig") == "sig")
+        .filter_map(|key| key.kid)
+        .collect())
+}
+
+// Asynchronously fetches JWKS from a specified URI, recording how long the request took
+async fn fetch_jwks<T: DeserializeOwned>(uri: &str) -> Result<T, Box<dyn Error>> {
+    let started = Instant::now();
+    let result = fetch_jwks_document(uri).await;
+    metrics::record_jwks_fetch(started.elapsed(), result.is_ok());
+    result
+}
+
+// Performs the JWKS request and deserializes the response body
+async fn fetch_jwks_document<T: DeserializeOwned>(uri: &str) -> Result<T, Box<dyn Error>> {
     // Perform the HTTP GET request
-    let res = match reqwest::get(uri).await {
+    let client = reqwest::Client::new();
+    let request = client.get(uri).build()?;
+    let res = match telemetry::send(&client, request).await {
         Ok(response) => response,
         Err(e) => {
             error!("Failed to fetch JWKS: {:?}", e);
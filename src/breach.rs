@@ -0,0 +1,354 @@
+//! # Breached Password Module
+//!
+//! This module checks candidate passwords against a local copy of the Have I Been Pwned corpus, so that
+//! passwords known to have appeared in data breaches can be rejected without ever leaving the server.
+//! Two on-disk formats are supported:
+//!     - A directory of SHA-1 range files, one `<PREFIX>.txt` per 5 character hash prefix, each containing
+//!       `SUFFIX:COUNT` lines exactly as served by the HIBP range API and its official downloader.
+//!     - A compact Bloom filter built from a full `HASH:COUNT` dump with the `build-breach-filter` command.
+//!
+//! The Bloom filter can report false positives (at the rate chosen when it was built) but never false negatives.
+
+use sha1::{Digest, Sha1};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+// Magic bytes identifying a filter file written by `BloomFilter::save`.
+const FILTER_MAGIC: &[u8; 4] = b"BPF1";
+
+// Length of a filter file's header: the magic bytes, the number of bits and the number of hashes.
+const FILTER_HEADER_LEN: u64 = 16;
+
+// Most hash functions a filter may use; optimal filters for any sensible rate use far fewer.
+const MAX_FILTER_HASHES: u32 = 64;
+
+/// A local breached password corpus, either as HIBP range files or as a Bloom filter.
+pub enum BreachedPasswords {
+    RangeFiles(PathBuf),
+    Bloom(BloomFilter),
+}
+
+impl BreachedPasswords {
+    /// Opens the corpus at `path`, treating directories as range files and regular files as Bloom filters.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if path.is_dir() {
+            info!(
+                "Using breached password range files from {}",
+                path.display()
+            );
+            Ok(BreachedPasswords::RangeFiles(path.to_path_buf()))
+        } else {
+            let filter = BloomFilter::load(path)?;
+            info!(
+                "Loaded breached password filter from {} ({} bits, {} hashes)",
+                path.display(),
+                filter.num_bits,
+                filter.num_hashes
+            );
+            Ok(BreachedPasswords::Bloom(filter))
+        }
+    }
+
+    /// Returns `true` if the password appears in the corpus.
+    pub fn contains(&self, password: &str) -> io::Result<bool> {
+        let digest: [u8; 20] = Sha1::digest(password.as_bytes()).into();
+
+        match self {
+            BreachedPasswords::Bloom(filter) => Ok(filter.contains(&digest)),
+            BreachedPasswords::RangeFiles(dir) => {
+                let hex_digest = hex::encode_upper(digest);
+                let (prefix, suffix) = hex_digest.split_at(5);
+                let range_file = dir.join(format!("{}.txt", prefix));
+
+                let file = match File::open(&range_file) {
+                    Ok(file) => file,
+                    // A missing range file means no breached hash shares this prefix.
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+                    Err(e) => return Err(e),
+                };
+
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    if let Some((candidate, _count)) = line.trim().split_once(':') {
+                        if candidate.eq_ignore_ascii_case(suffix) {
+                            debug!("Password hash prefix {} found in range file", prefix);
+                            return Ok(true);
+                        }
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// A Bloom filter keyed directly by SHA-1 digests.
+///
+/// Bit positions are derived from the digest itself using double hashing, so no further
+/// hashing is needed at lookup time.
+pub struct BloomFilter {
+    num_bits: u64,
+    num_hashes: u32,
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    /// Creates an empty filter sized for `expected_items` entries at the given false positive rate,
+    /// which must be between 0 and 1 (see `parse_false_positive_rate`).
+    pub fn with_rate(expected_items: u64, false_positive_rate: f64) -> Self {
+        let items = expected_items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(items * false_positive_rate.ln()) / (ln2 * ln2))
+            .ceil()
+            .max(8.0) as u64;
+        let num_hashes = ((num_bits as f64 / items) * ln2).round().max(1.0) as u32;
+
+        BloomFilter {
+            num_bits,
+            num_hashes,
+            bits: vec![0; num_bits.div_ceil(8) as usize],
+        }
+    }
+
+    /// Adds a SHA-1 digest to the filter.
+    pub fn insert(&mut self, digest: &[u8; 20]) {
+        for index in self.bit_indexes(digest) {
+            self.bits[(index / 8) as usize] |= 1 << (index % 8);
+        }
+    }
+
+    /// Returns `true` if the digest may have been inserted, `false` if it definitely was not.
+    pub fn contains(&self, digest: &[u8; 20]) -> bool {
+        self.bit_indexes(digest)
+            .all(|index| self.bits[(index / 8) as usize] & (1 << (index % 8)) != 0)
+    }
+
+    fn bit_indexes(&self, digest: &[u8; 20]) -> impl Iterator<Item = u64> {
+        let mut first = [0u8; 8];
+        let mut second = [0u8; 8];
+        first.copy_from_slice(&digest[0..8]);
+        second.copy_from_slice(&digest[8..16]);
+        let h1 = u64::from_be_bytes(first);
+        let h2 = u64::from_be_bytes(second) | 1;
+        let num_bits = self.num_bits;
+
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+
+    /// Reads a filter previously written with `save`.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != FILTER_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a breached password filter file",
+            ));
+        }
+
+        let mut num_bits = [0u8; 8];
+        let mut num_hashes = [0u8; 4];
+        reader.read_exact(&mut num_bits)?;
+        reader.read_exact(&mut num_hashes)?;
+        let num_bits = u64::from_le_bytes(num_bits);
+        let num_hashes = u32::from_le_bytes(num_hashes);
+
+        // An empty filter or one without hashes would match every password, and the header must agree
+        // with the file's length before the bits are allocated.
+        let invalid = |reason: String| Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+        if num_bits == 0 || num_hashes == 0 || num_hashes > MAX_FILTER_HASHES {
+            return invalid(format!(
+                "invalid breached password filter header ({} bits, {} hashes)",
+                num_bits, num_hashes
+            ));
+        }
+        let file_len = reader.get_ref().metadata()?.len();
+        if file_len != FILTER_HEADER_LEN + num_bits.div_ceil(8) {
+            return invalid(format!(
+                "breached password filter file is {} bytes long, expected {} for {} bits",
+                file_len,
+                FILTER_HEADER_LEN + num_bits.div_ceil(8),
+                num_bits
+            ));
+        }
+
+        let mut bits = vec![0; num_bits.div_ceil(8) as usize];
+        reader.read_exact(&mut bits)?;
+
+        Ok(BloomFilter {
+            num_bits,
+            num_hashes,
+            bits,
+        })
+    }
+
+    /// Writes the filter to `path`, replacing any existing file.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(FILTER_MAGIC)?;
+        writer.write_all(&self.num_bits.to_le_bytes())?;
+        writer.write_all(&self.num_hashes.to_le_bytes())?;
+        writer.write_all(&self.bits)?;
+        writer.flush()
+    }
+}
+
+/// Parses a false positive rate for `build_filter`, which must be greater than 0 and less than 1.
+pub fn parse_false_positive_rate(value: &str) -> Result<f64, String> {
+    let rate: f64 = value
+        .parse()
+        .map_err(|_| format!("{:?} is not a number", value))?;
+    if rate > 0.0 && rate < 1.0 {
+        Ok(rate)
+    } else {
+        Err(format!(
+            "{} is not a rate between 0 and 1 (exclusive), such as 0.001",
+            value
+        ))
+    }
+}
+
+/// Builds a Bloom filter from a downloaded `HASH:COUNT` dump and writes it to `output`.
+///
+/// The dump is read twice: once to count entries for sizing the filter, and once to fill it.
+/// Returns the number of hashes added.
+pub fn build_filter(input: &Path, output: &Path, false_positive_rate: f64) -> io::Result<u64> {
+    info!("Counting entries in {}", input.display());
+    let expected_items = BufReader::new(File::open(input)?)
+        .lines()
+        .filter(|line| line.as_ref().map_or(true, |l| !l.trim().is_empty()))
+        .count() as u64;
+
+    let mut filter = BloomFilter::with_rate(expected_items, false_positive_rate);
+    info!(
+        "Building filter for {} entries ({} bits, {} hashes)",
+        expected_items, filter.num_bits, filter.num_hashes
+    );
+
+    let mut added = 0;
+    for (line_number, line) in BufReader::new(File::open(input)?).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let hash = line.split(':').next().unwrap_or_default();
+        let mut digest = [0u8; 20];
+        hex::decode_to_slice(hash, &mut digest).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: invalid SHA-1 hash: {}", line_number + 1, e),
+            )
+        })?;
+        filter.insert(&digest);
+        added += 1;
+    }
+
+    if let Some(parent) = output.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    filter.save(output)?;
+    info!("Wrote breached password filter to {}", output.display());
+
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A directory for the files of one test, removed when the test starts.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("breach-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sha1_hex(password: &str) -> String {
+        hex::encode_upper(Sha1::digest(password.as_bytes()))
+    }
+
+    #[test]
+    fn filter_has_no_false_negatives_and_survives_a_round_trip() {
+        let dir = test_dir("filter");
+        let passwords: Vec<String> = (0..1000).map(|i| format!("password{}", i)).collect();
+        let dump: String = passwords
+            .iter()
+            .map(|p| format!("{}:{}\n", sha1_hex(p), 1))
+            .collect();
+        fs::write(dir.join("dump.txt"), dump).unwrap();
+
+        let added = build_filter(&dir.join("dump.txt"), &dir.join("filter.bin"), 0.001).unwrap();
+        assert_eq!(added, 1000);
+        let corpus = BreachedPasswords::open(&dir.join("filter.bin")).unwrap();
+        assert!(passwords.iter().all(|p| corpus.contains(p).unwrap()));
+        let false_positives = (0..1000)
+            .filter(|i| corpus.contains(&format!("not-breached-{}", i)).unwrap())
+            .count();
+        assert!(false_positives < 20, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn looks_up_passwords_in_range_files() {
+        let dir = test_dir("ranges");
+        let hash = sha1_hex("hunter2");
+        let (prefix, suffix) = hash.split_at(5);
+        fs::write(
+            dir.join(format!("{}.txt", prefix)),
+            format!(
+                "0000000000000000000000000000000000A:3\r\n{}:17\r\n",
+                suffix.to_lowercase()
+            ),
+        )
+        .unwrap();
+
+        let corpus = BreachedPasswords::open(&dir).unwrap();
+        assert!(corpus.contains("hunter2").unwrap());
+        assert!(!corpus.contains("correct horse battery staple").unwrap());
+    }
+
+    #[test]
+    fn rejects_corrupt_filter_files() {
+        let dir = test_dir("corrupt");
+        let header = |num_bits: u64, num_hashes: u32| {
+            let mut file = FILTER_MAGIC.to_vec();
+            file.extend(num_bits.to_le_bytes());
+            file.extend(num_hashes.to_le_bytes());
+            file
+        };
+        let mut empty = header(0, 7);
+        empty.extend([0xff; 8]);
+        let mut no_hashes = header(64, 0);
+        no_hashes.extend([0xff; 8]);
+        let mut huge = header(u64::MAX, 7);
+        huge.extend([0xff; 8]);
+        let mut truncated = header(64, 7);
+        truncated.extend([0xff; 4]);
+
+        for (name, file) in [
+            ("empty", empty),
+            ("no-hashes", no_hashes),
+            ("huge", huge),
+            ("truncated", truncated),
+        ] {
+            let path = dir.join(name);
+            fs::write(&path, file).unwrap();
+            let err = BloomFilter::load(&path).err().expect(name);
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_false_positive_rates_outside_zero_and_one() {
+        assert_eq!(parse_false_positive_rate("0.001"), Ok(0.001));
+        for rate in ["0", "-0.1", "1", "1.5", "NaN", "inf", "one"] {
+            assert!(parse_false_positive_rate(rate).is_err(), "{}", rate);
+        }
+    }
+}
@@ -0,0 +1,428 @@
+//! # Bulk Import and Export Module
+//!
+//! This module imports users in bulk from CSV or JSON and exports them without secrets. It backs both the
+//! admin endpoints and the `import-users` / `export-users` commands.
+//!
+//! Each imported record carries either a plaintext `password`, which is checked against the password policy
+//! and hashed, or a pre-hashed `password_hash` in any format `verify_password` accepts (this service's Argon2
+//! hashes or the legacy formats). Every record is validated on its own and failures are reported per row,
+//! so one bad row does not abort the rest of the import. In dry-run mode nothing is written.
+
+use crate::email::{normalize as normalize_email, NormalizedEmail};
+use crate::errors::ServiceError;
+use crate::models::{NewUser, User};
+use crate::password_policy::PasswordPolicy;
+use crate::schema::users::dsl::*;
+use crate::utils::{hash_password, is_supported_hash};
+use crate::validation::{field_errors, validate_email, validate_name};
+use crate::Pool;
+use actix_web::web;
+use diesel::dsl::insert_into;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tracing::{info, warn};
+use validator::Validate;
+
+/// Serialization format of an import or export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DataFormat {
+    Csv,
+    Json,
+}
+
+impl DataFormat {
+    /// MIME type of data in this format.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            DataFormat::Csv => "text/csv",
+            DataFormat::Json => "application/json",
+        }
+    }
+}
+
+/// A single user record to import.
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ImportRecord {
+    #[validate(length(min = 1, max = 100), custom(function = "validate_name"))]
+    pub first_name: String,
+    #[validate(length(min = 1, max = 100), custom(function = "validate_name"))]
+    pub last_name: String,
+    #[validate(length(max = 254), custom(function = "validate_email"))]
+    pub email: String,
+    #[validate(length(min = 8, max = 1024))]
+    pub password: Option<String>, // Plaintext password, hashed on import.
+    #[validate(length(max = 1024))]
+    pub password_hash: Option<String>, // Pre-hashed password, stored as-is.
+    pub created_at: Option<chrono::NaiveDateTime>, // Defaults to the import time.
+}
+
+/// A user record as exported, without the password hash.
+#[derive(Debug, Serialize)]
+pub struct ExportedUser {
+    pub id: i32,
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+/// Why a single row could not be imported.
+#[derive(Debug, Serialize)]
+pub struct RowError {
+    pub row: usize,            // 1-based position of the record in the input.
+    pub email: Option<String>, // Email of the record, if it could be read.
+    pub error: String,
+}
+
+/// Outcome of an import.
+#[derive(Debug, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub total: usize,
+    pub imported: usize, // Rows written, or rows that would be written in dry-run mode.
+    pub failed: usize,
+    pub errors: Vec<RowError>,
+}
+
+/// Parses import data, returning one result per record so malformed rows can be reported individually.
+///
+/// A JSON document that is not an array of objects is rejected as a whole.
+pub fn parse_records(
+    format: DataFormat,
+    data: &[u8],
+) -> Result<Vec<Result<ImportRecord, String>>, ServiceError> {
+    match format {
+        DataFormat::Csv => {
+            let mut reader = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_reader(data);
+            Ok(reader
+                .deserialize::<ImportRecord>()
+                .map(|record| record.map_err(|e| e.to_string()))
+                .collect())
+        }
+        DataFormat::Json => {
+            let values: Vec<serde_json::Value> = serde_json::from_slice(data).map_err(|e| {
+                ServiceError::BadRequest(format!("Invalid JSON import data: {}", e))
+            })?;
+            Ok(values
+                .into_iter()
+                .map(|value| serde_json::from_value(value).map_err(|e| e.to_string()))
+                .collect())
+        }
+    }
+}
+
+// A validated record ready for hashing and insertion.
+struct PendingUser {
+    row: usize,
+    record: ImportRecord,
+    email: NormalizedEmail,
+}
+
+// Checks the fields of a single record and returns its normalized email address.
+fn validate_record(record: &ImportRecord) -> Result<NormalizedEmail, String> {
+    if let Err(e) = record.validate() {
+        let fields = field_errors(&e);
+        return Err(fields
+            .iter()
+            .map(|(field, messages)| format!("{}: {}", field, messages.join(", ")))
+            .collect::<Vec<_>>()
+            .join("; "));
+    }
+    let normalized_email = normalize_email(&record.email)?;
+
+    match (&record.password, &record.password_hash) {
+        (Some(_), Some(_)) => Err("only one of password and password_hash may be set".to_string()),
+        (None, None) => Err("one of password and password_hash is required".to_string()),
+        (None, Some(hash)) if !is_supported_hash(hash) => {
+            Err("password_hash is not in a supported format".to_string())
+        }
+        _ => Ok(normalized_email),
+    }
+}
+
+/// Imports parsed records into the `users` table.
+///
+/// # Arguments
+///
+/// * `pool`: Database connection pool.
+/// * `policy`: Password policy applied to plaintext passwords.
+/// * `records`: Parsed records, as returned by `parse_records`.
+/// * `dry_run`: Validate only, without hashing or writing anything.
+///
+/// # Returns
+///
+/// A report with the number of imported rows and the reason each failed row was rejected.
+pub async fn import_users(
+    pool: web::Data<Pool>,
+    policy: web::Data<PasswordPolicy>,
+    records: Vec<Result<ImportRecord, String>>,
+    dry_run: bool,
+) -> Result<ImportReport, ServiceError> {
+    let total = records.len();
+    let mut errors = Vec::new();
+    let mut pending = Vec::new();
+    let mut seen_emails = HashSet::new();
+
+    // Validate each record on its own, including duplicates within the input.
+    for (index, record) in records.into_iter().enumerate() {
+        let row = index + 1;
+        let mut record = match record {
+            Ok(record) => record,
+            Err(error) => {
+                errors.push(RowError {
+                    row,
+                    email: None,
+                    error,
+                });
+                continue;
+            }
+        };
+        record.password = record.password.filter(|p| !p.is_empty());
+        record.password_hash = record.password_hash.filter(|h| !h.is_empty());
+
+        let validation = validate_record(&record).and_then(|normalized_email| {
+            if seen_emails.insert(normalized_email.key.clone()) {
+                Ok(normalized_email)
+            } else {
+                Err("email appears more than once in the input".to_string())
+            }
+        });
+        match validation {
+            Ok(normalized_email) => pending.push(PendingUser {
+                row,
+                record,
+                email: normalized_email,
+            }),
+            Err(error) => errors.push(RowError {
+                row,
+                email: Some(record.email),
+                error,
+            }),
+        }
+    }
+
+    // Reject records whose email is already registered.
+    let candidate_keys: Vec<String> = pending.iter().map(|p| p.email.key.clone()).collect();
+    let lookup_pool = pool.clone();
+    let existing: HashSet<Option<String>> = web::block(move || {
+        let mut conn = lookup_pool.get().map_err(ServiceError::Pool)?;
+        users
+            .filter(email_normalized.eq_any(&candidate_keys))
+            .select(email_normalized)
+            .load::<Option<String>>(&mut conn)
+            .map_err(ServiceError::Diesel)
+    })
+    .await??
+    .into_iter()
+    .collect();
+
+    let mut new_users = Vec::new();
+    for PendingUser {
+        row,
+        record,
+        email: normalized_email,
+    } in pending
+    {
+        if existing.contains(&Some(normalized_email.key.clone())) {
+            errors.push(RowError {
+                row,
+                email: Some(record.email),
+                error: "a user with this email already exists".to_string(),
+            });
+            continue;
+        }
+
+        // Plaintext passwords must satisfy the password policy and are hashed unless this is a dry run.
+        let stored_password = match (record.password, record.password_hash) {
+            (Some(password), _) => {
+                let check_policy = policy.clone();
+                let candidate = password.clone();
+                if let Err(e) = web::block(move || check_policy.check(&candidate)).await? {
+                    errors.push(RowError {
+                        row,
+                        email: Some(record.email),
+                        error: match e {
+                            ServiceError::BadRequest(message) => message,
+                            e => return Err(e),
+                        },
+                    });
+                    continue;
+                }
+                if dry_run {
+                    String::new()
+                } else {
+                    hash_password(&password).await.map_err(|e| {
+                        warn!("Password hashing failed during import: {:?}", e);
+                        ServiceError::InternalServerError
+                    })?
+                }
+            }
+            (None, Some(hash)) => hash,
+            (None, None) => unreachable!("validated above"),
+        };
+
+        new_users.push((
+            row,
+            NewUser {
+                first_name: record.first_name,
+                last_name: record.last_name,
+                email: normalized_email.address,
+                email_normalized: normalized_email.key,
+                user_password: stored_password,
+                created_at: record
+                    .created_at
+                    .unwrap_or_else(|| chrono::Local::now().naive_local()),
+            },
+        ));
+    }
+
+    // Insert row by row so a failure only affects its own row.
+    let mut imported = new_users.len();
+    if !dry_run {
+        let insert_errors = web::block(move || {
+            let mut conn = pool.get().map_err(ServiceError::Pool)?;
+            let mut insert_errors = Vec::new();
+            for (row, new_user) in &new_users {
+                if let Err(e) = insert_into(users).values(new_user).execute(&mut conn) {
+                    insert_errors.push(RowError {
+                        row: *row,
+                        email: Some(new_user.email.clone()),
+                        error: e.to_string(),
+                    });
+                }
+            }
+            Ok::<_, ServiceError>(insert_errors)
+        })
+        .await??;
+        imported -= insert_errors.len();
+        errors.extend(insert_errors);
+    }
+
+    errors.sort_by_key(|e| e.row);
+    info!(
+        "User import finished: {} of {} rows {}",
+        imported,
+        total,
+        if dry_run {
+            "valid (dry run)"
+        } else {
+            "imported"
+        }
+    );
+
+    Ok(ImportReport {
+        dry_run,
+        total,
+        imported,
+        failed: errors.len(),
+        errors,
+    })
+}
+
+/// Exports all users that have not deleted their account, without password hashes, in the given format.
+pub async fn export_users(
+    pool: web::Data<Pool>,
+    format: DataFormat,
+) -> Result<Vec<u8>, ServiceError> {
+    let all_users = web::block(move || {
+        let mut conn = pool.get().map_err(ServiceError::Pool)?;
+        users
+            .filter(deleted_at.is_null())
+            .order(id.asc())
+            .load::<User>(&mut conn)
+            .map_err(ServiceError::Diesel)
+    })
+    .await??;
+
+    let exported: Vec<ExportedUser> = all_users
+        .into_iter()
+        .map(|user| ExportedUser {
+            id: user.id,
+            first_name: user.first_name,
+            last_name: user.last_name,
+            email: user.email,
+            created_at: user.created_at,
+        })
+        .collect();
+    info!("Exporting {} users", exported.len());
+
+    match format {
+        DataFormat::Json => {
+            serde_json::to_vec(&exported).map_err(|_| ServiceError::InternalServerError)
+        }
+        DataFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            for user in &exported {
+                writer
+                    .serialize(user)
+                    .map_err(|_| ServiceError::InternalServerError)?;
+            }
+            writer
+                .into_inner()
+                .map_err(|_| ServiceError::InternalServerError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_csv_with_empty_optional_fields() {
+        let data = b"first_name,last_name,email,password,password_hash,created_at\n\
+            Ada,Lovelace,ada@example.com,correct horse,,\n\
+            Alan,Turing,alan@example.com,,$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW,2024-02-04T08:05:16\n\
+            broken,row\n";
+
+        let records = parse_records(DataFormat::Csv, data).unwrap();
+        assert_eq!(records.len(), 3);
+
+        let ada = records[0].as_ref().unwrap();
+        assert_eq!(ada.password.as_deref(), Some("correct horse"));
+        assert_eq!(ada.password_hash, None);
+        assert_eq!(ada.created_at, None);
+
+        let alan = records[1].as_ref().unwrap();
+        assert_eq!(alan.password, None);
+        assert!(validate_record(alan).is_ok());
+        assert!(alan.created_at.is_some());
+
+        assert!(records[2].is_err());
+    }
+
+    #[test]
+    fn requires_exactly_one_password_source() {
+        let records = parse_records(
+            DataFormat::Json,
+            br#"[
+                {"first_name": "A", "last_name": "B", "email": "a@example.com"},
+                {"first_name": "A", "last_name": "B", "email": "a@example.com",
+                 "password": "correct horse",
+                 "password_hash": "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW"},
+                {"first_name": "A", "last_name": "B", "email": "a@example.com", "password_hash": "$2b$04$abc"},
+                {"first_name": "A", "last_name": "B", "email": "a@example.com",
+                 "password_hash": "$pbkdf2-sha256$4000000000$AAECAwQFBgcICQoLDA0ODw$ySUUCEVchXk5SrsGEYEvrVNkMO5aIThOIMEEhXCkxgI"},
+                {"first_name": "A", "last_name": "B", "email": "not-an-email", "password": "correct horse"},
+                {"first_name": "A", "last_name": "B", "email": "a@example.com", "password": "correct horse"}
+            ]"#,
+        )
+        .unwrap();
+
+        let results: Vec<_> = records
+            .iter()
+            .map(|r| validate_record(r.as_ref().unwrap()))
+            .collect();
+        assert!(results[0].is_err());
+        assert!(results[1].is_err());
+        assert!(results[2].is_err());
+        assert!(results[3].is_err());
+        assert!(results[4].is_err());
+        assert!(results[5].is_ok());
+    }
+}
@@ -0,0 +1,78 @@
+//! # CLI Module
+//!
+//! This module defines the command line interface. Running the binary without a subcommand starts the
+//! HTTP server; subcommands perform one-off maintenance tasks and exit. The global flags override the
+//! corresponding settings (see the settings module) for the server and every subcommand.
+
+use crate::breach::parse_false_positive_rate;
+use crate::bulk::DataFormat;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+/// Command line arguments for the application.
+#[derive(Debug, Parser)]
+#[command(version, about = "Authentication service using JWT from Auth0")]
+pub struct Cli {
+    /// Configuration file (TOML or YAML) to read settings from.
+    #[arg(long, global = true, env = "CONFIG_FILE", value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Run mode, overriding `run_mode` from the configuration file and environment.
+    #[arg(long, global = true)]
+    pub run_mode: Option<String>,
+    /// Address and port to listen on, overriding `server_address`.
+    #[arg(long, global = true)]
+    pub server_address: Option<String>,
+    /// Database connection URL, overriding `database_url`.
+    #[arg(long, global = true)]
+    pub database_url: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Maintenance tasks that can be run instead of starting the server.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Build a breached password Bloom filter from a downloaded `HASH:COUNT` SHA-1 dump.
+    BuildBreachFilter {
+        /// Path to the downloaded Have I Been Pwned SHA-1 dump.
+        #[arg(long)]
+        input: PathBuf,
+        /// Where to write the filter; point `BREACHED_PASSWORDS_PATH` at this file.
+        #[arg(long)]
+        output: PathBuf,
+        /// Acceptable false positive rate of the filter, between 0 and 1.
+        #[arg(long, default_value_t = 0.001, value_parser = parse_false_positive_rate)]
+        false_positive_rate: f64,
+    },
+    /// Import users from a CSV or JSON file, reporting failures per row.
+    ImportUsers {
+        /// File holding the users, as CSV with a header row or as a JSON array.
+        #[arg(long)]
+        file: PathBuf,
+        /// Format of the file; inferred from its extension when omitted.
+        #[arg(long, value_enum)]
+        format: Option<DataFormat>,
+        /// Validate the file without writing anything.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Export all users, without password hashes.
+    ExportUsers {
+        /// Output format.
+        #[arg(long, value_enum, default_value_t = DataFormat::Json)]
+        format: DataFormat,
+        /// Where to write the export; defaults to standard output.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Erase accounts whose deletion grace period has passed, as the server does periodically.
+    PurgeDeletedUsers,
+    /// Recompute the case-insensitive email identity of every account and report duplicates.
+    ReconcileEmails {
+        /// Store the recomputed identities instead of only reporting them.
+        #[arg(long)]
+        apply: bool,
+    },
+    /// Check the hash chain of the audit log and report the first event that was tampered with.
+    VerifyAuditLog,
+}
@@ -0,0 +1,184 @@
+//! # Client IP Module
+//!
+//! This module determines the IP address a request came from, as recorded in the audit log, the login
+//! history and the request logs. The address is the peer of the connection. Only when the peer is one of the
+//! reverse proxies listed in `TRUSTED_PROXIES` is the `X-Forwarded-For` header consulted: its addresses are
+//! read from the right, skipping trusted proxies, and the first other address is the client. Clients can put
+//! anything in the header themselves, so it is ignored on requests that did not pass through a trusted proxy.
+
+use crate::settings::Settings;
+use actix_web::web::Data;
+use actix_web::HttpRequest;
+use std::net::IpAddr;
+
+/// Header in which reverse proxies pass on the addresses a request was forwarded for.
+pub const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// A proxy address or range of addresses, such as `10.0.0.1` or `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    /// Parses an IPv4 or IPv6 address, optionally followed by a prefix length.
+    pub fn parse(network: &str) -> Result<Self, String> {
+        let network = network.trim();
+        let invalid = || format!("{:?} is not an IP address or CIDR range", network);
+        let (address, prefix_len) = match network.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (network, None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| invalid())?;
+        let max_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse::<u8>().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(Network {
+            address,
+            prefix_len,
+        })
+    }
+
+    /// Returns true if the address lies in the network.
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parses a comma-separated list of proxy addresses and ranges, such as `10.0.0.0/8, 192.0.2.1`.
+pub fn parse_networks(list: &str) -> Result<Vec<Network>, String> {
+    list.split(',')
+        .filter(|network| !network.trim().is_empty())
+        .map(Network::parse)
+        .collect()
+}
+
+/// Returns the address of the client, given the peer of the connection and the `X-Forwarded-For` header.
+pub fn resolve(
+    trusted_proxies: &[Network],
+    peer: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+) -> Option<IpAddr> {
+    let peer = peer?;
+    let is_trusted = |address: IpAddr| trusted_proxies.iter().any(|proxy| proxy.contains(address));
+    if !is_trusted(peer) {
+        return Some(peer);
+    }
+    let Some(forwarded_for) = forwarded_for else {
+        return Some(peer);
+    };
+
+    let mut client = peer;
+    for hop in forwarded_for.rsplit(',') {
+        // Entries that are not addresses cannot be attributed to anyone; the last proxy is used instead.
+        let Ok(address) = hop.trim().parse::<IpAddr>() else {
+            break;
+        };
+        client = address;
+        if !is_trusted(address) {
+            break;
+        }
+    }
+    Some(client)
+}
+
+/// Returns the address of the client a request came from, as text.
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    let trusted_proxies = req
+        .app_data::<Data<Settings>>()
+        .map(|settings| settings.trusted_proxies.as_slice())
+        .unwrap_or_default();
+    let forwarded_for = req
+        .headers()
+        .get(FORWARDED_FOR_HEADER)
+        .and_then(|value| value.to_str().ok());
+    resolve(
+        trusted_proxies,
+        req.peer_addr().map(|peer| peer.ip()),
+        forwarded_for,
+    )
+    .map(|address| address.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> Option<IpAddr> {
+        Some(address.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_addresses_and_ranges() {
+        let networks = parse_networks("10.0.0.0/8, 192.0.2.1,2001:db8::/32").unwrap();
+        assert_eq!(networks.len(), 3);
+        assert!(networks[0].contains("10.1.2.3".parse().unwrap()));
+        assert!(!networks[0].contains("11.0.0.1".parse().unwrap()));
+        assert!(networks[1].contains("192.0.2.1".parse().unwrap()));
+        assert!(!networks[1].contains("192.0.2.2".parse().unwrap()));
+        assert!(networks[2].contains("2001:db8::1".parse().unwrap()));
+        assert!(Network::parse("0.0.0.0/0")
+            .unwrap()
+            .contains("203.0.113.7".parse().unwrap()));
+
+        assert!(parse_networks("10.0.0.0/33").is_err());
+        assert!(parse_networks("proxy.internal").is_err());
+        assert!(parse_networks("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_trusts_forwarded_addresses_from_trusted_proxies() {
+        let proxies = parse_networks("10.0.0.0/8").unwrap();
+
+        // Clients connecting directly cannot choose their address.
+        assert_eq!(
+            resolve(&proxies, ip("203.0.113.7"), Some("198.51.100.1")),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            resolve(&[], ip("10.0.0.2"), Some("198.51.100.1")),
+            ip("10.0.0.2")
+        );
+
+        // Behind trusted proxies, the nearest address that is not a proxy is the client, whatever the
+        // client wrote to the left of it.
+        assert_eq!(
+            resolve(
+                &proxies,
+                ip("10.0.0.2"),
+                Some("198.51.100.1, 203.0.113.7, 10.0.0.3")
+            ),
+            ip("203.0.113.7")
+        );
+        assert_eq!(resolve(&proxies, ip("10.0.0.2"), None), ip("10.0.0.2"));
+        assert_eq!(
+            resolve(&proxies, ip("10.0.0.2"), Some("10.0.0.4")),
+            ip("10.0.0.4")
+        );
+        assert_eq!(
+            resolve(&proxies, ip("10.0.0.2"), Some("spoofed, 10.0.0.3")),
+            ip("10.0.0.3")
+        );
+        assert_eq!(resolve(&proxies, None, Some("198.51.100.1")), None);
+    }
+}
@@ -0,0 +1,310 @@
+//! # Cookie Sessions Module
+//!
+//! This module lets browser clients authenticate with a session cookie instead of a bearer token, so a web
+//! frontend never has to keep a token where its scripts can read it. Logging in with `?mode=cookie` starts a
+//! session identified by a random id, sent in an HttpOnly cookie and stored only as a hash in the `sessions`
+//! table. Requests that may change state must also repeat the session's CSRF token, which is readable from a
+//! second cookie, in the `X-CSRF-Token` header (double-submit). A session expires after a period without
+//! requests, each request extending it, and at the latest a fixed time after login.
+//!
+//! Which kinds of sessions a scope of routes accepts is chosen with an `AuthMode`.
+
+use crate::auth::AuthenticatedUser;
+use crate::errors::ServiceError;
+use crate::models::{Session, User};
+use crate::sessions::{self, SessionKind, LAST_USED_RESOLUTION_SECS};
+use crate::telemetry;
+use crate::Pool;
+use actix_web::cookie::{self, Cookie, SameSite};
+use actix_web::dev::ServiceRequest;
+use actix_web::http::Method;
+use actix_web::web::Data;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::NaiveDateTime;
+use rand_core::{OsRng, RngCore};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+/// Name of the HttpOnly cookie holding the session id.
+pub const SESSION_COOKIE: &str = "session";
+
+/// Name of the cookie holding the CSRF token, readable by the frontend's scripts.
+pub const CSRF_COOKIE: &str = "csrf_token";
+
+/// Header in which requests that may change state repeat the CSRF token.
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+// Defaults for how long sessions last without requests and after login.
+const DEFAULT_IDLE_TIMEOUT_SECS: i64 = 1800;
+const DEFAULT_MAX_LIFETIME_SECS: i64 = 86_400;
+
+// Number of random bytes in a session id.
+const SESSION_ID_BYTES: usize = 32;
+
+/// Kinds of sessions accepted by a scope of routes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    /// Only bearer tokens, in the `Authorization` header.
+    Bearer,
+    /// Only session cookies.
+    Cookie,
+    /// Bearer tokens, or session cookies from requests without an `Authorization` header.
+    Both,
+}
+
+impl AuthMode {
+    /// Returns true if sessions of the given kind are accepted.
+    pub fn accepts(self, kind: SessionKind) -> bool {
+        match self {
+            AuthMode::Bearer => kind == SessionKind::Bearer,
+            AuthMode::Cookie => kind == SessionKind::Cookie,
+            AuthMode::Both => true,
+        }
+    }
+}
+
+/// The `SameSite` attribute of the session cookies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSitePolicy {
+    Strict,
+    Lax,
+    None,
+}
+
+/// Settings of cookie sessions, configured with `SESSION_COOKIE_SECURE`, `SESSION_COOKIE_SAME_SITE`
+/// (`strict`, `lax` or `none`), `SESSION_IDLE_TIMEOUT_SECS` and `SESSION_MAX_LIFETIME_SECS`.
+#[derive(Debug, Clone, Copy)]
+pub struct CookiePolicy {
+    pub secure: bool, // Whether the cookies are only sent over HTTPS.
+    pub same_site: SameSitePolicy,
+    pub idle_timeout: chrono::Duration, // Time without requests after which a session expires.
+    pub max_lifetime: chrono::Duration, // Time after login after which a session expires anyway.
+}
+
+impl Default for CookiePolicy {
+    /// Secure, strictly same-site cookies for sessions lasting 30 minutes without requests and 24 hours
+    /// at most.
+    fn default() -> Self {
+        CookiePolicy {
+            secure: true,
+            same_site: SameSitePolicy::Strict,
+            idle_timeout: chrono::Duration::seconds(DEFAULT_IDLE_TIMEOUT_SECS),
+            max_lifetime: chrono::Duration::seconds(DEFAULT_MAX_LIFETIME_SECS),
+        }
+    }
+}
+
+impl CookiePolicy {
+    /// Returns when a session created at `created_at` and last used at `now` expires.
+    pub fn expiry(&self, created_at: NaiveDateTime, now: NaiveDateTime) -> NaiveDateTime {
+        (now + self.idle_timeout).min(created_at + self.max_lifetime)
+    }
+
+    /// Returns the cookies that hand a session to the browser, or update their expiry.
+    pub fn session_cookies(
+        &self,
+        session_id: &str,
+        expires_at: NaiveDateTime,
+        now: NaiveDateTime,
+    ) -> [Cookie<'static>; 2] {
+        let max_age = cookie::time::Duration::seconds((expires_at - now).num_seconds().max(0));
+        let mut session = self.cookie(SESSION_COOKIE, session_id.to_string());
+        session.set_http_only(true);
+        session.set_max_age(max_age);
+        let mut csrf = self.cookie(CSRF_COOKIE, csrf_token(session_id));
+        csrf.set_max_age(max_age);
+        [session, csrf]
+    }
+
+    /// Returns the cookies that remove a session from the browser.
+    pub fn removal_cookies(&self) -> [Cookie<'static>; 2] {
+        [SESSION_COOKIE, CSRF_COOKIE].map(|name| {
+            let mut cookie = self.cookie(name, String::new());
+            cookie.make_removal();
+            cookie
+        })
+    }
+
+    fn cookie(&self, name: &'static str, value: String) -> Cookie<'static> {
+        Cookie::build(name, value)
+            .path("/")
+            .secure(self.secure)
+            .same_site(match self.same_site {
+                SameSitePolicy::Strict => SameSite::Strict,
+                SameSitePolicy::Lax => SameSite::Lax,
+                SameSitePolicy::None => SameSite::None,
+            })
+            .finish()
+    }
+}
+
+/// Generates the id of a new session: 256 random bits, base64url-encoded.
+pub fn generate_session_id() -> String {
+    let mut bytes = [0u8; SESSION_ID_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Returns the CSRF token of a session.
+///
+/// The token is derived from the secret session id, so a token planted in the CSRF cookie by another
+/// site or subdomain does not match the session.
+pub fn csrf_token(session_id: &str) -> String {
+    let digest = Sha256::new()
+        .chain_update(b"csrf-token:")
+        .chain_update(session_id.as_bytes())
+        .finalize();
+    URL_SAFE_NO_PAD.encode(digest)
+}
+
+/// Authenticates a request by its session cookie, returning the signed-in user and, if the request
+/// extended the session, the cookies carrying its new expiry.
+///
+/// The session must be active and of an account that is not locked, and requests with methods other than
+/// `GET`, `HEAD` and `OPTIONS` must carry the session's CSRF token in the `X-CSRF-Token` header and cookie.
+pub async fn authenticate(
+    req: &ServiceRequest,
+    policy: CookiePolicy,
+) -> Result<(AuthenticatedUser, Option<[Cookie<'static>; 2]>), ServiceError> {
+    let session_id = req
+        .cookie(SESSION_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .ok_or(ServiceError::Unauthorized)?;
+    check_csrf(req, &session_id)?;
+
+    let pool = req
+        .app_data::<Data<Pool>>()
+        .cloned()
+        .ok_or(ServiceError::InternalServerError)?;
+    let secret = session_id.clone();
+    let (session, user, extended) = telemetry::db_block("find_cookie_session", move || {
+        let mut conn = pool.get().map_err(ServiceError::Pool)?;
+        let (session, user) = sessions::find_session(&mut conn, SessionKind::Cookie, &secret)
+            .map_err(ServiceError::Diesel)?
+            .ok_or(ServiceError::Unauthorized)?;
+        let extended = slide_expiry(&mut conn, &session, &user, policy)?;
+        Ok::<_, ServiceError>((session, user, extended))
+    })
+    .await??;
+
+    let now = chrono::Local::now().naive_local();
+    Ok((
+        AuthenticatedUser {
+            user_id: user.id,
+            session_id: session.id,
+            kind: SessionKind::Cookie,
+        },
+        extended.map(|expires_at| policy.session_cookies(&session_id, expires_at, now)),
+    ))
+}
+
+// Rejects sessions that cannot be used, then moves the expiry of the session unless that was already done
+// in the last few minutes, returning the new expiry if it moved.
+fn slide_expiry(
+    conn: &mut diesel::PgConnection,
+    session: &Session,
+    user: &User,
+    policy: CookiePolicy,
+) -> Result<Option<NaiveDateTime>, ServiceError> {
+    if !session.is_active() {
+        return Err(ServiceError::Unauthorized);
+    }
+    user.check_not_locked()?;
+    let now = chrono::Local::now().naive_local();
+
+    // Short idle timeouts are extended more often, so that sessions in use do not expire.
+    let resolution =
+        chrono::Duration::seconds(LAST_USED_RESOLUTION_SECS).min(policy.idle_timeout / 10);
+    let stale = session
+        .last_used_at
+        .is_none_or(|last_used_at| now - last_used_at >= resolution);
+    if !stale {
+        return Ok(None);
+    }
+    let expires_at = policy.expiry(session.created_at, now);
+    sessions::extend_session(conn, session.id, expires_at).map_err(ServiceError::Diesel)?;
+    Ok(Some(expires_at))
+}
+
+// Requests that may change state must repeat the session's CSRF token from its cookie in a header.
+fn check_csrf(req: &ServiceRequest, session_id: &str) -> Result<(), ServiceError> {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return Ok(());
+    }
+    let header = req
+        .headers()
+        .get(CSRF_HEADER)
+        .map(|value| value.as_bytes().to_vec());
+    let cookie = req.cookie(CSRF_COOKIE);
+    match (header, cookie) {
+        (Some(header), Some(cookie))
+            if bool::from(header.ct_eq(cookie.value().as_bytes()))
+                && bool::from(header.ct_eq(csrf_token(session_id).as_bytes())) =>
+        {
+            Ok(())
+        }
+        _ => Err(ServiceError::CsrfTokenMismatch),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn request(method: Method, header: Option<&str>, cookie: &str) -> ServiceRequest {
+        let mut req = TestRequest::default()
+            .method(method)
+            .cookie(Cookie::new(CSRF_COOKIE, cookie.to_string()));
+        if let Some(header) = header {
+            req = req.insert_header((CSRF_HEADER, header));
+        }
+        req.to_srv_request()
+    }
+
+    #[test]
+    fn requires_the_session_csrf_token_on_unsafe_methods() {
+        let session_id = generate_session_id();
+        let token = csrf_token(&session_id);
+        let planted = csrf_token(&generate_session_id());
+        let check = |req: ServiceRequest| check_csrf(&req, &session_id).is_ok();
+
+        assert!(check(request(Method::GET, None, "")));
+        assert!(check(request(Method::POST, Some(&token), &token)));
+        assert!(!check(request(Method::DELETE, None, &token)));
+        assert!(!check(request(Method::POST, Some(&token), "other")));
+        assert!(!check(request(Method::POST, Some(&planted), &planted)));
+    }
+
+    #[test]
+    fn slides_the_expiry_up_to_the_maximum_lifetime() {
+        let policy = CookiePolicy::default();
+        let created_at = chrono::NaiveDate::from_ymd_opt(2024, 4, 19)
+            .unwrap()
+            .and_hms_opt(9, 0, 0)
+            .unwrap();
+
+        let soon = created_at + chrono::Duration::hours(1);
+        assert_eq!(policy.expiry(created_at, soon), soon + policy.idle_timeout);
+        let late = created_at + chrono::Duration::hours(23) + chrono::Duration::minutes(50);
+        assert_eq!(
+            policy.expiry(created_at, late),
+            created_at + policy.max_lifetime
+        );
+
+        let [session, csrf] = policy.session_cookies("id", soon + policy.idle_timeout, soon);
+        assert_eq!(session.http_only(), Some(true));
+        assert_eq!(session.secure(), Some(true));
+        assert_eq!(session.same_site(), Some(SameSite::Strict));
+        assert_eq!(csrf.http_only(), None);
+        assert_eq!(csrf.value(), csrf_token("id"));
+        assert_eq!(
+            csrf.max_age(),
+            Some(cookie::time::Duration::seconds(DEFAULT_IDLE_TIMEOUT_SECS))
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! # CORS Module
+//!
+//! This module lets browser frontends served from other origins call the API. Each scope of routes has its
+//! own `CorsPolicy`: the user routes are configured with the `USERS_CORS_*` settings and the admin routes
+//! with the `ADMIN_CORS_*` settings. Origins are allowed exactly (`https://app.example.com`) or with all of
+//! their subdomains (`https://*.example.com`). A scope without allowed origins answers no cross-origin
+//! requests, which is the default.
+//!
+//! Origins are only checked by browsers: requests from other origins are still served, just without the
+//! headers that would let a page read the response. Clients that are not browsers are not affected.
+
+use actix_cors::Cors;
+use actix_web::http::header::HeaderName;
+use actix_web::http::Method;
+
+// Methods and request headers allowed by default, covering every route of the API.
+const DEFAULT_METHODS: &str = "GET, POST, DELETE";
+const DEFAULT_HEADERS: &str = "Authorization, Content-Type, X-CSRF-Token, X-Request-Id";
+
+// How long browsers may cache the answer to a preflight request by default.
+const DEFAULT_MAX_AGE_SECS: u32 = 3600;
+
+/// An origin allowed to make cross-origin requests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OriginPattern {
+    /// A single origin, such as `https://app.example.com`.
+    Exact(String),
+    /// Every subdomain of a domain, such as `https://*.example.com`, stored as the scheme and the part
+    /// after the wildcard (`.example.com`, with the port if there is one).
+    Subdomains { scheme: String, suffix: String },
+}
+
+impl OriginPattern {
+    /// Parses an origin or wildcard origin, which must have an `http` or `https` scheme and no path.
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        let pattern = pattern.trim().to_ascii_lowercase();
+        let invalid = |reason: &str| Err(format!("{:?} {}", pattern, reason));
+        let Some((scheme, host)) = pattern.split_once("://") else {
+            return invalid("is not an origin such as https://app.example.com");
+        };
+        if scheme != "http" && scheme != "https" {
+            return invalid("must use the http or https scheme");
+        }
+        let (name, port) = match host.rsplit_once(':') {
+            Some((name, port)) => (name, Some(port)),
+            None => (host, None),
+        };
+        if port.is_some_and(|port| port.parse::<u16>().is_err()) {
+            return invalid("has an invalid port");
+        }
+
+        match name.strip_prefix("*.") {
+            Some(domain) => {
+                // `*.com` would let anyone register an allowed origin.
+                if !is_host(domain) || !domain.contains('.') {
+                    return invalid("must name a domain with at least two labels after `*.`");
+                }
+                Ok(OriginPattern::Subdomains {
+                    scheme: scheme.to_string(),
+                    suffix: host[1..].to_string(),
+                })
+            }
+            None if is_host(name) => Ok(OriginPattern::Exact(pattern)),
+            None => invalid("is not an origin such as https://app.example.com"),
+        }
+    }
+
+    /// Returns true if the value of an `Origin` header matches the pattern.
+    pub fn matches(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        match self {
+            OriginPattern::Exact(allowed) => origin == *allowed,
+            OriginPattern::Subdomains { scheme, suffix } => origin
+                .strip_prefix(scheme.as_str())
+                .and_then(|rest| rest.strip_prefix("://"))
+                .and_then(|host| host.strip_suffix(suffix.as_str()))
+                .is_some_and(is_host),
+        }
+    }
+}
+
+// Whether a string is a host name: dot-separated labels of letters, digits and hyphens.
+fn is_host(name: &str) -> bool {
+    !name.is_empty()
+        && name.split('.').all(|label| {
+            !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Cross-origin access to a scope of routes, configured with `<SCOPE>_CORS_ALLOWED_ORIGINS`,
+/// `<SCOPE>_CORS_ALLOWED_METHODS`, `<SCOPE>_CORS_ALLOWED_HEADERS`, `<SCOPE>_CORS_ALLOW_CREDENTIALS` and
+/// `<SCOPE>_CORS_MAX_AGE_SECS`.
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    pub allowed_origins: Vec<OriginPattern>,
+    pub allowed_methods: Vec<Method>,
+    pub allowed_headers: Vec<HeaderName>,
+    pub allow_credentials: bool, // Whether pages may send cookies and read responses to requests with them.
+    pub max_age: u32,            // Seconds browsers may cache the answer to a preflight request.
+}
+
+impl Default for CorsPolicy {
+    /// No allowed origins, with the methods and headers used by the API.
+    fn default() -> Self {
+        CorsPolicy {
+            allowed_origins: Vec::new(),
+            allowed_methods: parse_methods(DEFAULT_METHODS).expect("default methods must be valid"),
+            allowed_headers: parse_headers(DEFAULT_HEADERS).expect("default headers must be valid"),
+            allow_credentials: false,
+            max_age: DEFAULT_MAX_AGE_SECS,
+        }
+    }
+}
+
+impl CorsPolicy {
+    /// Returns true if requests from the origin are allowed.
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|pattern| pattern.matches(origin))
+    }
+
+    /// Builds the middleware applying the policy to a scope.
+    ///
+    /// Responses expose the `X-Request-Id` header, so pages can report it along with errors.
+    pub fn middleware(&self) -> Cors {
+        let policy = self.clone();
+        let mut cors = Cors::default()
+            .allowed_origin_fn(move |origin, _| {
+                origin
+                    .to_str()
+                    .is_ok_and(|origin| policy.allows_origin(origin))
+            })
+            .allowed_methods(self.allowed_methods.clone())
+            .allowed_headers(self.allowed_headers.clone())
+            .expose_headers([HeaderName::from_static("x-request-id")])
+            .max_age(self.max_age as usize);
+        if self.allow_credentials {
+            cors = cors.supports_credentials();
+        }
+        cors
+    }
+}
+
+/// Parses a comma-separated list of allowed origins.
+pub fn parse_origins(list: &str) -> Result<Vec<OriginPattern>, String> {
+    split(list).map(OriginPattern::parse).collect()
+}
+
+/// Parses a comma-separated list of HTTP methods, such as `GET, POST`.
+pub fn parse_methods(list: &str) -> Result<Vec<Method>, String> {
+    split(list)
+        .map(|method| {
+            Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                .map_err(|_| format!("{:?} is not an HTTP method", method))
+        })
+        .collect()
+}
+
+/// Parses a comma-separated list of request header names, such as `Authorization, Content-Type`.
+pub fn parse_headers(list: &str) -> Result<Vec<HeaderName>, String> {
+    split(list)
+        .map(|name| {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("{:?} is not a header name", name))
+        })
+        .collect()
+}
+
+// Splits a comma-separated list, ignoring blank entries.
+fn split(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    #[test]
+    fn matches_exact_and_wildcard_origins() {
+        let origins = parse_origins("https://app.example.com, https://*.example.org:8443").unwrap();
+        let policy = CorsPolicy {
+            allowed_origins: origins,
+            ..CorsPolicy::default()
+        };
+
+        assert!(policy.allows_origin("https://app.example.com"));
+        assert!(policy.allows_origin("https://App.Example.com"));
+        assert!(!policy.allows_origin("http://app.example.com"));
+        assert!(!policy.allows_origin("https://app.example.com:444"));
+        assert!(policy.allows_origin("https://a.b.example.org:8443"));
+        assert!(!policy.allows_origin("https://example.org:8443"));
+        assert!(!policy.allows_origin("https://evilexample.org:8443"));
+        assert!(!policy.allows_origin("https://a.example.org"));
+        assert!(!policy.allows_origin("https://.example.org:8443"));
+    }
+
+    #[test]
+    fn rejects_invalid_origins() {
+        assert!(OriginPattern::parse("app.example.com").is_err());
+        assert!(OriginPattern::parse("ftp://app.example.com").is_err());
+        assert!(OriginPattern::parse("https://app.example.com/").is_err());
+        assert!(OriginPattern::parse("https://*.com").is_err());
+        assert!(OriginPattern::parse("*").is_err());
+        assert!(OriginPattern::parse("https://app.example.com:99999").is_err());
+        assert!(parse_methods("GET, FETCH ME").is_err());
+    }
+
+    #[actix_rt::test]
+    async fn answers_preflight_requests_from_allowed_origins() {
+        let policy = CorsPolicy {
+            allowed_origins: parse_origins("https://*.example.com").unwrap(),
+            allow_credentials: true,
+            ..CorsPolicy::default()
+        };
+        let app = init_service(
+            App::new().service(
+                web::scope("/users")
+                    .wrap(policy.middleware())
+                    .route("/me", web::delete().to(HttpResponse::NoContent)),
+            ),
+        )
+        .await;
+        let preflight = |origin: &str| {
+            TestRequest::default()
+                .method(Method::OPTIONS)
+                .uri("/users/me")
+                .insert_header((header::ORIGIN, origin))
+                .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "DELETE"))
+                .insert_header((header::ACCESS_CONTROL_REQUEST_HEADERS, "x-csrf-token"))
+                .to_request()
+        };
+
+        let res = call_service(&app, preflight("https://app.example.com")).await;
+        assert!(res.status().is_success());
+        let headers = res.headers();
+        assert_eq!(
+            headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            "https://app.example.com"
+        );
+        assert_eq!(
+            headers
+                .get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS)
+                .unwrap(),
+            "true"
+        );
+        assert_eq!(headers.get(header::ACCESS_CONTROL_MAX_AGE).unwrap(), "3600");
+
+        let res = call_service(&app, preflight("https://example.net")).await;
+        assert!(!res
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        // Requests that are not from a browser are served as before.
+        let res = call_service(&app, TestRequest::delete().uri("/users/me").to_request()).await;
+        assert_eq!(res.status(), 204);
+    }
+}
@@ -0,0 +1,130 @@
+//! # Personal Data Export Module
+//!
+//! This module assembles everything the service stores about a user into a single JSON document, for
+//! subject access requests. Secrets such as password hashes and session token hashes are never included.
+
+use crate::models::{Login, User};
+use crate::schema::{audit_event_clients, audit_events, logins, sessions, users};
+use diesel::prelude::*;
+use serde::Serialize;
+
+/// A user's personal data as stored by the service.
+#[derive(Debug, Serialize)]
+pub struct PersonalDataExport {
+    pub generated_at: chrono::NaiveDateTime,
+    pub profile: ExportedProfile,
+    pub sessions: Vec<ExportedSession>,
+    pub logins: Vec<Login>,
+    pub audit_events: Vec<ExportedAuditEvent>,
+}
+
+/// The user's row in `users`, without the password hash.
+#[derive(Debug, Serialize)]
+pub struct ExportedProfile {
+    pub id: i32,
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub disabled_at: Option<chrono::NaiveDateTime>,
+    pub suspended_until: Option<chrono::NaiveDateTime>,
+    pub disabled_reason: Option<String>,
+    pub password_reset_required: bool,
+    pub deleted_at: Option<chrono::NaiveDateTime>,
+}
+
+/// A token issued to the user at login, without the token hash.
+#[derive(Debug, Serialize, Queryable)]
+pub struct ExportedSession {
+    pub id: i32,
+    pub kind: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub expires_at: chrono::NaiveDateTime,
+    pub revoked_at: Option<chrono::NaiveDateTime>,
+    pub last_used_at: Option<chrono::NaiveDateTime>,
+}
+
+/// A security event concerning the user, without the hashes linking it into the audit chain.
+#[derive(Debug, Serialize, Queryable)]
+pub struct ExportedAuditEvent {
+    pub occurred_at: chrono::NaiveDateTime,
+    pub event_type: String,
+    pub outcome: String,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    #[serde(serialize_with = "serialize_details")]
+    pub details: String,
+}
+
+// Writes the stored JSON details as an object rather than as a string.
+fn serialize_details<S: serde::Serializer>(
+    details: &str,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let details: serde_json::Value = serde_json::from_str(details).unwrap_or_default();
+    details.serialize(serializer)
+}
+
+/// Collects the personal data of a user, or `None` if the user does not exist.
+pub fn export_user_data(
+    conn: &mut PgConnection,
+    user_id: i32,
+) -> QueryResult<Option<PersonalDataExport>> {
+    conn.build_transaction().read_only().run(|conn| {
+        let Some(user) = users::table.find(user_id).first::<User>(conn).optional()? else {
+            return Ok(None);
+        };
+
+        let sessions = sessions::table
+            .filter(sessions::user_id.eq(user_id))
+            .order(sessions::created_at.asc())
+            .select((
+                sessions::id,
+                sessions::kind,
+                sessions::created_at,
+                sessions::expires_at,
+                sessions::revoked_at,
+                sessions::last_used_at,
+            ))
+            .load::<ExportedSession>(conn)?;
+
+        let logins = logins::table
+            .filter(logins::user_id.eq(user_id))
+            .order(logins::logged_in_at.asc())
+            .select(Login::as_select())
+            .load(conn)?;
+
+        let audit_events = audit_events::table
+            .left_join(audit_event_clients::table)
+            .filter(audit_events::target_user_id.eq(user_id))
+            .order(audit_events::id.asc())
+            .select((
+                audit_events::occurred_at,
+                audit_events::event_type,
+                audit_events::outcome,
+                audit_event_clients::ip_address.nullable(),
+                audit_event_clients::user_agent.nullable(),
+                audit_events::details,
+            ))
+            .load::<ExportedAuditEvent>(conn)?;
+
+        Ok(Some(PersonalDataExport {
+            generated_at: chrono::Local::now().naive_local(),
+            profile: ExportedProfile {
+                id: user.id,
+                first_name: user.first_name,
+                last_name: user.last_name,
+                email: user.email,
+                created_at: user.created_at,
+                disabled_at: user.disabled_at,
+                suspended_until: user.suspended_until,
+                disabled_reason: user.disabled_reason,
+                password_reset_required: user.password_reset_required,
+                deleted_at: user.deleted_at,
+            },
+            sessions,
+            logins,
+            audit_events,
+        }))
+    })
+}
@@ -0,0 +1,263 @@
+//! # Email Normalization Module
+//!
+//! This module defines how email addresses identify accounts. Addresses are normalized on every write and
+//! lookup: surrounding whitespace is trimmed and the domain is converted to its lowercase ASCII (IDNA) form.
+//! The identity key stored in `users.email_normalized` additionally lowercases the local part, so
+//! `Bob@Example.com` and `bob@example.com` refer to the same account while the address is still stored
+//! as the user entered it.
+
+use diesel::prelude::*;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+
+// Key of the advisory lock held while identity keys are filled in at startup.
+const BACKFILL_LOCK: i64 = 0x656d_6169_6c73; // "emails"
+
+/// An email address in normalized form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NormalizedEmail {
+    /// The address as stored and displayed: trimmed, with the domain in lowercase ASCII form.
+    pub address: String,
+    /// The case-insensitive identity key the `users.email_normalized` unique index is built on.
+    pub key: String,
+}
+
+/// Normalizes an email address, rejecting values that are not a `local@domain` address.
+pub fn normalize(raw: &str) -> Result<NormalizedEmail, String> {
+    let trimmed = raw.trim();
+    let (local, domain) = trimmed
+        .rsplit_once('@')
+        .ok_or_else(|| "email is not a valid address".to_string())?;
+    if local.is_empty() || domain.is_empty() || local.chars().any(char::is_whitespace) {
+        return Err("email is not a valid address".to_string());
+    }
+
+    let domain = idna::domain_to_ascii(domain)
+        .map_err(|_| "email domain is not a valid domain name".to_string())?;
+    let is_hostname = domain
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+    if !is_hostname || (!domain.contains('.') && domain != "localhost") {
+        return Err("email domain is not a valid domain name".to_string());
+    }
+
+    Ok(NormalizedEmail {
+        address: format!("{}@{}", local, domain),
+        key: format!("{}@{}", local.to_lowercase(), domain),
+    })
+}
+
+/// An account involved in an email reconciliation finding.
+#[derive(Debug, Serialize)]
+pub struct AccountRef {
+    pub id: i32,
+    pub email: String,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+// Id, email, identity key and creation time of an account, as loaded for reconciliation.
+type StoredAccount = (i32, String, Option<String>, chrono::NaiveDateTime);
+
+/// Accounts whose addresses normalize to the same identity key.
+#[derive(Debug, Serialize)]
+pub struct DuplicateGroup {
+    pub key: String,
+    pub kept: AccountRef,            // Oldest account, which holds the identity.
+    pub duplicates: Vec<AccountRef>, // Accounts left without an identity, to be merged or removed by hand.
+}
+
+/// An account whose stored address cannot be normalized.
+#[derive(Debug, Serialize)]
+pub struct InvalidEmail {
+    pub account: AccountRef,
+    pub error: String,
+}
+
+/// Outcome of reconciling stored email identities.
+#[derive(Debug, Serialize)]
+pub struct ReconcileReport {
+    pub applied: bool,
+    pub checked: usize,
+    pub updated: usize, // Accounts whose identity key was, or would be, corrected.
+    pub duplicates: Vec<DuplicateGroup>,
+    pub invalid: Vec<InvalidEmail>,
+}
+
+/// Recomputes the identity key of every account that has not been anonymized and reports accounts
+/// whose addresses collide or cannot be normalized.
+///
+/// The oldest account of each colliding group keeps the identity; the others have their key cleared,
+/// which makes them unreachable by email until they are resolved. Stored addresses are not rewritten.
+/// Nothing is changed unless `apply` is set.
+pub fn reconcile(conn: &mut PgConnection, apply: bool) -> QueryResult<ReconcileReport> {
+    use crate::schema::users::dsl::*;
+
+    conn.transaction(|conn| {
+        let accounts: Vec<StoredAccount> = users
+            .filter(anonymized_at.is_null())
+            .order((created_at.asc(), id.asc()))
+            .select((id, email, email_normalized, created_at))
+            .load(conn)?;
+        let checked = accounts.len();
+
+        // Group accounts by their recomputed key, oldest first.
+        let mut groups: BTreeMap<String, Vec<StoredAccount>> = BTreeMap::new();
+        let mut changes: Vec<(i32, Option<String>)> = Vec::new();
+        let mut invalid = Vec::new();
+        for account in accounts {
+            match normalize(&account.1) {
+                Ok(normalized) => groups.entry(normalized.key).or_default().push(account),
+                Err(error) => {
+                    if account.2.is_some() {
+                        changes.push((account.0, None));
+                    }
+                    invalid.push(InvalidEmail {
+                        account: AccountRef {
+                            id: account.0,
+                            email: account.1,
+                            created_at: account.3,
+                        },
+                        error,
+                    });
+                }
+            }
+        }
+
+        // Decide the key every account should hold.
+        let mut duplicates = Vec::new();
+        for (key, mut group) in groups {
+            let (kept_id, kept_email, kept_key, kept_created_at) = group.remove(0);
+            for (dup_id, _, dup_key, _) in &group {
+                if dup_key.is_some() {
+                    changes.push((*dup_id, None));
+                }
+            }
+            if kept_key.as_deref() != Some(key.as_str()) {
+                changes.push((kept_id, Some(key.clone())));
+            }
+            if !group.is_empty() {
+                duplicates.push(DuplicateGroup {
+                    key,
+                    kept: AccountRef {
+                        id: kept_id,
+                        email: kept_email,
+                        created_at: kept_created_at,
+                    },
+                    duplicates: group
+                        .into_iter()
+                        .map(|(dup_id, dup_email, _, dup_created_at)| AccountRef {
+                            id: dup_id,
+                            email: dup_email,
+                            created_at: dup_created_at,
+                        })
+                        .collect(),
+                });
+            }
+        }
+        if apply {
+            // Clear keys before setting new ones so no intermediate state violates the unique index.
+            changes.sort_by_key(|(_, key)| key.is_some());
+            for (user_id, key) in &changes {
+                diesel::update(users.find(user_id))
+                    .set(email_normalized.eq(key))
+                    .execute(conn)?;
+            }
+        }
+
+        Ok(ReconcileReport {
+            applied: apply,
+            checked,
+            updated: changes.len(),
+            duplicates,
+            invalid,
+        })
+    })
+}
+
+/// Gives every account without an identity key the key of its address, unless another account already
+/// holds it, and returns how many accounts were updated.
+///
+/// The server runs this at startup, so accounts created before identity keys existed can log in with
+/// the same normalization as new ones. Of several accounts normalizing to the same key, the oldest gets
+/// it. Accounts whose address cannot be normalized, or whose key is taken, are left without one; the
+/// former can still log in with their exact address, and `reconcile` reports both. Servers starting at
+/// the same time take turns, and only the keys of accounts still missing one are loaded.
+pub fn backfill(conn: &mut PgConnection) -> QueryResult<usize> {
+    use crate::schema::users::dsl::*;
+
+    conn.transaction(|conn| {
+        diesel::sql_query("SELECT pg_advisory_xact_lock($1)")
+            .bind::<diesel::sql_types::BigInt, _>(BACKFILL_LOCK)
+            .execute(conn)?;
+
+        let missing: Vec<(i32, String)> = users
+            .filter(email_normalized.is_null())
+            .filter(anonymized_at.is_null())
+            .order((created_at.asc(), id.asc()))
+            .select((id, email))
+            .load(conn)?;
+        let candidates: Vec<(i32, String)> = missing
+            .into_iter()
+            .filter_map(|(user_id, address)| Some((user_id, normalize(&address).ok()?.key)))
+            .collect();
+        if candidates.is_empty() {
+            return Ok(0);
+        }
+
+        let keys: Vec<&String> = candidates.iter().map(|(_, key)| key).collect();
+        let mut taken: HashSet<String> = users
+            .filter(email_normalized.eq_any(keys))
+            .select(email_normalized.assume_not_null())
+            .load::<String>(conn)?
+            .into_iter()
+            .collect();
+
+        let mut updated = 0;
+        for (user_id, key) in candidates {
+            if taken.insert(key.clone()) {
+                diesel::update(users.find(user_id))
+                    .set(email_normalized.eq(key))
+                    .execute(conn)?;
+                updated += 1;
+            }
+        }
+        Ok(updated)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn case_and_whitespace_variants_share_a_key() {
+        let a = normalize("  Bob@Example.COM ").unwrap();
+        let b = normalize("bob@example.com").unwrap();
+        assert_eq!(a.address, "Bob@example.com");
+        assert_eq!(a.key, b.key);
+    }
+
+    #[test]
+    fn internationalized_domains_are_converted_to_ascii() {
+        let email = normalize("anna@Bücher.example").unwrap();
+        assert_eq!(email.address, "anna@xn--bcher-kva.example");
+        assert_eq!(
+            email.key,
+            normalize("anna@xn--bcher-kva.example").unwrap().key
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        for raw in [
+            "",
+            "bob",
+            "@example.com",
+            "bob@",
+            "b ob@example.com",
+            "bob@exa mple.com",
+        ] {
+            assert!(normalize(raw).is_err(), "{:?} should be rejected", raw);
+        }
+    }
+}
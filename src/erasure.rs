@@ -0,0 +1,157 @@
+//! # Account Erasure Module
+//!
+//! This module implements the right to erasure. Deleting an account through `DELETE /users/me` only
+//! soft-deletes it: the user can no longer log in and their sessions are revoked, but the row is kept for a
+//! grace period (`ACCOUNT_RETENTION_DAYS`) during which an admin can restore it. A background job then erases
+//! accounts whose grace period has passed, either by anonymizing their personally identifying columns or by
+//! deleting the row, depending on `ACCOUNT_ERASURE_MODE`.
+
+use crate::audit;
+use crate::errors::ServiceError;
+use crate::schema::users::dsl::*;
+use crate::sessions;
+use crate::Pool;
+use actix_web::web;
+use diesel::prelude::*;
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::{error, info};
+
+// Defaults for the grace period and the interval between erasure runs.
+const DEFAULT_RETENTION_DAYS: i64 = 30;
+const DEFAULT_PURGE_INTERVAL_SECS: u64 = 3600;
+
+/// How accounts are erased once their grace period has passed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErasureMode {
+    /// Overwrite the personally identifying columns and keep the row, so references to it stay valid.
+    Anonymize,
+    /// Delete the row along with everything referencing it.
+    Delete,
+}
+
+/// Settings of the erasure job, configured with `ACCOUNT_RETENTION_DAYS`, `ACCOUNT_ERASURE_MODE`
+/// (`anonymize` or `delete`) and `ACCOUNT_PURGE_INTERVAL_SECS`.
+#[derive(Debug, Clone, Copy)]
+pub struct ErasurePolicy {
+    pub retention_days: i64,
+    pub mode: ErasureMode,
+    pub interval: Duration,
+}
+
+impl Default for ErasurePolicy {
+    /// A 30 day grace period after which accounts are anonymized, checked hourly.
+    fn default() -> Self {
+        ErasurePolicy {
+            retention_days: DEFAULT_RETENTION_DAYS,
+            mode: ErasureMode::Anonymize,
+            interval: Duration::from_secs(DEFAULT_PURGE_INTERVAL_SECS),
+        }
+    }
+}
+
+/// Soft-deletes an account and revokes its sessions.
+///
+/// Returns `false` if the user does not exist or was already deleted.
+pub fn soft_delete_user(conn: &mut PgConnection, user_id: i32) -> QueryResult<bool> {
+    conn.transaction(|conn| {
+        let deleted = diesel::update(users.find(user_id).filter(deleted_at.is_null()))
+            .set(deleted_at.eq(chrono::Local::now().naive_local()))
+            .execute(conn)?;
+        if deleted == 0 {
+            return Ok(false);
+        }
+
+        let revoked = sessions::revoke_user_sessions(conn, user_id)?;
+        info!(
+            "Soft-deleted user id {} and revoked {} sessions",
+            user_id, revoked
+        );
+        Ok(true)
+    })
+}
+
+/// Erases all soft-deleted accounts whose grace period has passed, returning how many were erased.
+pub fn purge_deleted_users(conn: &mut PgConnection, policy: &ErasurePolicy) -> QueryResult<usize> {
+    let now = chrono::Local::now().naive_local();
+    let cutoff = now - chrono::Duration::days(policy.retention_days);
+    let expired = users
+        .filter(deleted_at.le(cutoff))
+        .filter(anonymized_at.is_null());
+
+    conn.transaction(|conn| {
+        let expired_ids: Vec<i32> = expired.select(id).load(conn)?;
+        // Audit events outlive the account, but not the clients they were recorded with.
+        for &user_id in &expired_ids {
+            audit::erase_clients(conn, user_id)?;
+        }
+
+        match policy.mode {
+            ErasureMode::Delete => {
+                diesel::delete(users.filter(id.eq_any(&expired_ids))).execute(conn)?;
+            }
+            ErasureMode::Anonymize => {
+                for &user_id in &expired_ids {
+                    diesel::update(users.find(user_id))
+                        .set((
+                            first_name.eq(""),
+                            last_name.eq(""),
+                            // Keeps the unique constraint satisfied and frees the original address.
+                            email.eq(format!("deleted-{}@invalid", user_id)),
+                            email_normalized.eq(None::<String>),
+                            user_password.eq(""),
+                            password_reset_token_hash.eq(None::<String>),
+                            password_reset_expires_at.eq(None::<chrono::NaiveDateTime>),
+                            disabled_reason.eq(None::<String>),
+                            anonymized_at.eq(now),
+                        ))
+                        .execute(conn)?;
+                    diesel::delete(
+                        crate::schema::sessions::table
+                            .filter(crate::schema::sessions::user_id.eq(user_id)),
+                    )
+                    .execute(conn)?;
+                    diesel::delete(
+                        crate::schema::logins::table
+                            .filter(crate::schema::logins::user_id.eq(user_id)),
+                    )
+                    .execute(conn)?;
+                }
+            }
+        }
+        Ok(expired_ids.len())
+    })
+}
+
+/// Runs `purge_deleted_users` once on the blocking thread pool.
+pub async fn run_purge(
+    pool: web::Data<Pool>,
+    policy: ErasurePolicy,
+) -> Result<usize, ServiceError> {
+    web::block(move || {
+        let mut conn = pool.get().map_err(ServiceError::Pool)?;
+        purge_deleted_users(&mut conn, &policy).map_err(ServiceError::Diesel)
+    })
+    .await?
+}
+
+/// Starts the background job that periodically erases accounts whose grace period has passed.
+pub fn spawn_purge_job(pool: web::Data<Pool>, policy: ErasurePolicy) {
+    info!(
+        "Erasing deleted accounts after {} days ({:?}), checking every {:?}",
+        policy.retention_days, policy.mode, policy.interval
+    );
+
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(policy.interval);
+        loop {
+            interval.tick().await;
+            match run_purge(pool.clone(), policy).await {
+                Ok(0) => {}
+                Ok(erased) => info!("Erased {} deleted accounts", erased),
+                Err(e) => error!("Erasing deleted accounts failed: {:?}", e),
+            }
+        }
+    });
+}
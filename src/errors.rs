@@ -11,7 +11,12 @@ use actix_web::error::BlockingError;
 use actix_web::{error::ResponseError, HttpResponse};
 use diesel::result::Error as DieselError;
 use r2d2::Error as R2d2Error;
+use serde_json::json;
 use thiserror::Error; // Facilitates easy definition of error enums.
+use validator::ValidationErrors;
+
+use crate::request_id;
+use crate::validation::{field_errors, FieldErrors};
 
 // Define a comprehensive enum for various service errors that might occur within the application.
 #[derive(Error, Debug)]
@@ -23,10 +28,34 @@ pub enum ServiceError {
     #[error("Unauthorized")]
     Unauthorized,
 
+    // Represents an authenticated caller lacking the permissions for an action.
+    #[error("Forbidden")]
+    Forbidden,
+
+    // A request authenticated with a session cookie did not repeat its CSRF token.
+    #[error("CSRF token missing or invalid")]
+    CsrfTokenMismatch,
+
+    // The account has been disabled by an admin.
+    #[error("Account disabled")]
+    AccountDisabled,
+
+    // The account has been suspended by an admin until the given time.
+    #[error("Account suspended until {0}")]
+    AccountSuspended(chrono::NaiveDateTime),
+
+    // An admin requires the user to change their password before logging in again.
+    #[error("Password reset required")]
+    PasswordResetRequired,
+
     // Represents client-side input errors with a dynamic message.
     #[error("BadRequest: {0}")]
     BadRequest(String),
 
+    // Represents request fields that failed validation, with the failures of each field.
+    #[error("Validation failed: {0:?}")]
+    Validation(FieldErrors),
+
     // Represents errors related to environment configuration issues.
     #[error("Environment Error")]
     EnvironmentError,
@@ -51,6 +80,37 @@ pub enum ServiceError {
     Pool(#[from] R2d2Error),
 }
 
+// Errors that can occur while hashing or verifying passwords.
+#[derive(Error, Debug)]
+pub enum PasswordError {
+    // Hashing failed or a stored hash could not be parsed.
+    #[error("Password hash error: {0}")]
+    Hash(#[from] argon2::password_hash::Error),
+
+    // The stored hash is tagged with a pepper version missing from the keyring.
+    #[error("No secret key configured for pepper version {0}")]
+    UnknownPepper(u32),
+
+    // An imported legacy hash could not be parsed.
+    #[error("Malformed {0} hash")]
+    MalformedHash(&'static str),
+
+    // Verifying an imported bcrypt hash failed.
+    #[error("bcrypt error: {0}")]
+    Bcrypt(#[from] bcrypt::BcryptError),
+
+    // The hashing job could not be run on the blocking thread pool.
+    #[error("Password hashing is unavailable")]
+    Unavailable,
+}
+
+// Implements conversion from failed declarative validation to ServiceError.
+impl From<ValidationErrors> for ServiceError {
+    fn from(e: ValidationErrors) -> Self {
+        ServiceError::Validation(field_errors(&e))
+    }
+}
+
 // Implements conversion from Actix Web's BlockingError to ServiceError.
 impl From<BlockingError> for ServiceError {
     fn from(_e: BlockingError) -> Self {
@@ -59,31 +119,81 @@ impl From<BlockingError> for ServiceError {
 }
 
 // Implement how service errors are converted into HTTP responses.
+// Bodies carry the id of the request, so an error report can be matched with the server logs.
 impl ResponseError for ServiceError {
     fn error_response(&self) -> HttpResponse {
-        match self {
+        let (mut response, mut body) = match self {
             // Each variant maps to an appropriate HTTP response.
-            ServiceError::InternalServerError => HttpResponse::InternalServerError()
-                .json("Internal Server Error. Please try again later."),
-            ServiceError::BadRequest(message) => HttpResponse::BadRequest().json(message),
-            ServiceError::EnvironmentError => HttpResponse::InternalServerError()
-                .json("Configuration error. Please check server configurations."),
-            ServiceError::JWKSFetchError => HttpResponse::InternalServerError()
-                .json("Failed to fetch JWKS. Please check JWKS endpoint."),
-            ServiceError::TokenValidationError => {
-                HttpResponse::Unauthorized().json("Invalid token. Token validation failed.")
-            }
-            ServiceError::Diesel(_) => HttpResponse::InternalServerError()
-                .json("Database operation failed. Please try again later."),
-            ServiceError::Pool(_) => {
-                HttpResponse::InternalServerError().json("Database connection pool error.")
-            }
-            ServiceError::NotFound => {
-                HttpResponse::NotFound().json("The requested resource was not found.")
-            }
-            ServiceError::Unauthorized => {
-                HttpResponse::Unauthorized().json("Invalid credentials or password")
-            }
+            ServiceError::InternalServerError => (
+                HttpResponse::InternalServerError(),
+                message("Internal Server Error. Please try again later."),
+            ),
+            ServiceError::BadRequest(text) => (HttpResponse::BadRequest(), message(text)),
+            ServiceError::Validation(fields) => (
+                HttpResponse::UnprocessableEntity(),
+                json!({ "errors": fields }),
+            ),
+            ServiceError::EnvironmentError => (
+                HttpResponse::InternalServerError(),
+                message("Configuration error. Please check server configurations."),
+            ),
+            ServiceError::JWKSFetchError => (
+                HttpResponse::InternalServerError(),
+                message("Failed to fetch JWKS. Please check JWKS endpoint."),
+            ),
+            ServiceError::TokenValidationError => (
+                HttpResponse::Unauthorized(),
+                message("Invalid token. Token validation failed."),
+            ),
+            ServiceError::Diesel(_) => (
+                HttpResponse::InternalServerError(),
+                message("Database operation failed. Please try again later."),
+            ),
+            ServiceError::Pool(_) => (
+                HttpResponse::InternalServerError(),
+                message("Database connection pool error."),
+            ),
+            ServiceError::NotFound => (
+                HttpResponse::NotFound(),
+                message("The requested resource was not found."),
+            ),
+            ServiceError::Unauthorized => (
+                HttpResponse::Unauthorized(),
+                message("Invalid credentials or password"),
+            ),
+            ServiceError::AccountDisabled => (
+                HttpResponse::Forbidden(),
+                message("This account has been disabled."),
+            ),
+            ServiceError::AccountSuspended(until) => (
+                HttpResponse::Forbidden(),
+                message(&format!(
+                    "This account is suspended until {}.",
+                    until.format("%Y-%m-%d %H:%M:%S")
+                )),
+            ),
+            ServiceError::PasswordResetRequired => (
+                HttpResponse::Forbidden(),
+                message("A password change is required. Please change your password to continue."),
+            ),
+            ServiceError::Forbidden => (
+                HttpResponse::Forbidden(),
+                message("You do not have permission to perform this action."),
+            ),
+            ServiceError::CsrfTokenMismatch => (
+                HttpResponse::Forbidden(),
+                message("Missing or invalid CSRF token. Send the csrf_token cookie's value in the X-CSRF-Token header."),
+            ),
+        };
+
+        if let Some(request_id) = request_id::current() {
+            body["request_id"] = json!(request_id.as_str());
         }
+        response.json(body)
     }
 }
+
+// Body of an error response explained by a single message.
+fn message(text: &str) -> serde_json::Value {
+    json!({ "error": text })
+}
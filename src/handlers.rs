@@ -1,35 +1,80 @@
 //! # Handlers Module
 //!
 //! This module contains the request handlers for user operations such as signing up, logging in,
-//! and accessing the home page. It utilizes Actix Web for handling web requests and Diesel for database operations.
+//! changing passwords and accessing the home page. It utilizes Actix Web for handling web requests and Diesel for database operations.
 
 /// Dependencies
 /// Importing necessary modules and structs for handling database operations, web requests, and authentication.
-use super::models::{LoginCredentials, NewUser, User};
+use super::models::{LoginCredentials, NewUser, PasswordChange, PasswordReset, User};
 use super::schema::users::dsl::*;
 use super::Pool;
+use crate::cookie_sessions::{self, CookiePolicy};
 use crate::diesel::QueryDsl;
 use crate::diesel::RunQueryDsl;
+use crate::password_policy::PasswordPolicy;
+use crate::sessions::{self, SessionKind};
+use crate::settings::{Auth0Settings, Settings};
 use actix_web::{web, HttpResponse, Responder, Result as ActixResult};
 use diesel::dsl::insert_into;
-use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
 
-use crate::auth::request_auth0_token;
+use crate::audit::{self, AuditContext, Event, EventType};
+use crate::auth::{request_auth0_token, AuthenticatedUser};
+use crate::data_export;
 use crate::diesel::ExpressionMethods;
+use crate::email::normalize as normalize_email;
+use crate::erasure;
 use crate::errors::ServiceError;
-use crate::utils::{hash_password, verify_password};
+use crate::login_history::{self, LoginHistory};
+use crate::metrics;
+use crate::password_resets;
+use crate::telemetry;
+use crate::utils::{hash_password, needs_rehash, verify_dummy_password, verify_password};
+use crate::validation::{validate_email, validate_name};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
 use diesel::OptionalExtension;
+use validator::Validate;
+
+// How many tokens login requests before giving up when each is already recorded for another session,
+// and how long it waits in between. Auth0 timestamps tokens to the second, so the next one differs.
+const TOKEN_ATTEMPTS: u32 = 3;
+const TOKEN_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(1100);
 
 /// Struct for user input on sign-up.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
 pub struct InputUser {
+    #[validate(length(min = 1, max = 100), custom(function = "validate_name"))]
     pub first_name: String,
+    #[validate(length(min = 1, max = 100), custom(function = "validate_name"))]
     pub last_name: String,
+    #[validate(length(max = 254), custom(function = "validate_email"))]
     pub email: String,
+    #[validate(length(min = 8, max = 1024))]
     pub user_password: String,
 }
 
+/// A newly created user as returned by sign-up, without the password hash or account state.
+#[derive(Debug, Serialize)]
+pub struct SignedUpUser {
+    pub id: i32,
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+}
+
+impl From<User> for SignedUpUser {
+    fn from(user: User) -> Self {
+        SignedUpUser {
+            id: user.id,
+            first_name: user.first_name,
+            last_name: user.last_name,
+            email: user.email,
+        }
+    }
+}
+
 /// Handler for processing user sign-up requests.
 ///
 /// This asynchronous function takes a database connection pool and user input data,
@@ -38,28 +83,35 @@ pub struct InputUser {
 /// # Arguments
 ///
 /// * `db`: Database connection pool.
+/// * `policy`: Password policy applied to the chosen password.
 /// * `item`: User input data.
+/// * `context`: Client the request came from, for the audit log.
 ///
 /// # Returns
 ///
 /// This function returns an Actix result with either an HTTP response indicating success or a ServiceError.
-
 pub async fn sign_up(
-    db: web::Data<Pool>,        // Database connection pool
-    item: web::Json<InputUser>, // User input data
+    db: web::Data<Pool>,               // Database connection pool
+    policy: web::Data<PasswordPolicy>, // Password policy
+    item: web::Json<InputUser>,        // User input data
+    context: AuditContext,             // Client details for the audit log
 ) -> ActixResult<HttpResponse, ServiceError> {
-    // Validate input fields are not empty.
-    if item.first_name.is_empty()
-        || item.last_name.is_empty()
-        || item.email.is_empty()
-        || item.user_password.is_empty()
-    {
-        warn!("Signup failed: All fields are required.");
-        return Err(ServiceError::BadRequest(
-            "Invalid input: All fields are required".to_string(),
-        ));
+    // Validate the input fields.
+    if let Err(e) = item.validate() {
+        warn!("Signup failed: invalid input.");
+        return Err(e.into());
     }
 
+    // Normalize the email address, which identifies the account case-insensitively.
+    let normalized_email = normalize_email(&item.email).map_err(|e| {
+        warn!("Signup failed: {}.", e);
+        ServiceError::BadRequest(format!("Invalid input: {}", e))
+    })?;
+
+    // Check the chosen password against the password policy.
+    let candidate = item.user_password.clone();
+    web::block(move || policy.check(&candidate)).await??;
+
     // Hash the user's password for secure storage.
     let hashed_password = hash_password(&item.user_password)
         .await
@@ -69,20 +121,27 @@ pub async fn sign_up(
     input_user.user_password = hashed_password; // Update the input user with the hashed password.
 
     // Insert the new user into the database.
-    let user_result = web::block(move || {
-        let mut conn = db.get().map_err(ServiceError::Pool)?;
+    let insert_pool = db.clone();
+    let user_result = telemetry::db_block("insert_user", move || {
+        let mut conn = insert_pool.get().map_err(ServiceError::Pool)?;
 
         let new_user = NewUser {
             first_name: input_user.first_name,
             last_name: input_user.last_name,
-            email: input_user.email,
+            email: normalized_email.address,
+            email_normalized: normalized_email.key,
             user_password: input_user.user_password, // Use the hashed password here
             created_at: chrono::Local::now().naive_local(),
         };
         insert_into(users)
             .values(&new_user)
             .get_result::<User>(&mut conn)
-            .map_err(ServiceError::Diesel)
+            .map_err(|e| match e {
+                DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                    ServiceError::BadRequest("A user with this email already exists".to_string())
+                }
+                e => ServiceError::Diesel(e),
+            })
     })
     .await
     .map_err(|e: actix_web::error::BlockingError| ServiceError::from(e))?;
@@ -91,64 +150,133 @@ pub async fn sign_up(
     match user_result {
         Ok(user) => {
             info!("New user created with email: {}", user.email);
-            Ok(HttpResponse::Created().json(user))
+            let event = Event::success(EventType::Signup)
+                .by_user(user.id)
+                .target(user.id);
+            audit::record(db, &context, event).await;
+            Ok(HttpResponse::Created().json(SignedUpUser::from(user)))
         }
         Err(e) => {
             error!("Failed to create user: {:?}", e);
-            Err(e.into()) // Convert to ServiceError if not already
+            Err(e)
         }
     }
 }
 
+/// Query parameters of a login request.
+#[derive(Debug, Deserialize)]
+pub struct LoginQuery {
+    #[serde(default)]
+    pub mode: SessionKind, // `bearer` (default) for a token, `cookie` for a session cookie.
+}
+
 /// Handler for processing user login requests.
 ///
 /// This asynchronous function authenticates a user by their email and password.
-/// If authentication succeeds, it requests and returns an Auth0 token.
+/// If authentication succeeds, it requests and returns an Auth0 token, or with `?mode=cookie` starts
+/// a cookie session, and adds the login to the user's login history. The outcome of every attempt
+/// is counted in the login metrics and recorded in the audit log.
 ///
 /// # Arguments
 ///
 /// * `db`: Database connection pool.
+/// * `auth0`: Auth0 client settings used to request the token.
+/// * `settings`: Application settings, with the accepted kinds of sessions.
+/// * `history`: Login history recorder.
+/// * `query`: Kind of session to start.
 /// * `credentials`: User's login credentials.
+/// * `context`: Client the request came from, for the audit log and login history.
 ///
 /// # Returns
 ///
-/// This function returns an Actix result with either an HTTP response containing the Auth0 token or an ActixError.
-
+/// This function returns an Actix result with either an HTTP response containing the Auth0 token or
+/// the session cookies, or an ActixError.
 pub async fn login(
     db: web::Data<Pool>,                      // Database connection pool
+    auth0: web::Data<Auth0Settings>,          // Auth0 client settings
+    settings: web::Data<Settings>,            // Application settings
+    history: web::Data<LoginHistory>,         // Login history recorder
+    query: web::Query<LoginQuery>,            // Kind of session to start
     credentials: web::Json<LoginCredentials>, // User's login credentials
+    context: AuditContext,                    // Client details for the audit log
 ) -> ActixResult<HttpResponse, ServiceError> {
+    let mut account = None;
+    let mode = query.mode;
+    let result = match authenticate(db.clone(), &settings, mode, credentials, &mut account).await {
+        Ok(user) if mode == SessionKind::Cookie => {
+            let policy = settings.cookie_sessions;
+            start_cookie_session(db.clone(), policy, history, &user, &context).await
+        }
+        Ok(user) => issue_token(db.clone(), auth0, history, &user, &context).await,
+        Err(e) => Err(e),
+    };
+    metrics::record_login(&result, account.is_some());
+
+    let mut event = match &result {
+        Ok(_) => Event::success(EventType::Login),
+        Err(e) => Event::failure(EventType::Login).detail(
+            "reason",
+            metrics::login_failure_reason(e, account.is_some()),
+        ),
+    };
+    // Only a successful login proves that the user is the one acting.
+    if let Some(account) = account {
+        event = event.target(account);
+        if result.is_ok() {
+            event = event.by_user(account);
+        }
+    }
+    audit::record(db, &context, event).await;
+    result
+}
+
+/// Authenticates a login request, returning the user allowed to start a session of the given kind.
+/// `account` is set to the id of the account the email belongs to, once it is known.
+async fn authenticate(
+    db: web::Data<Pool>,                      // Database connection pool
+    settings: &Settings,                      // Application settings
+    mode: SessionKind,                        // Kind of session to start
+    credentials: web::Json<LoginCredentials>, // User's login credentials
+    account: &mut Option<i32>,                // Id of the account logged into
+) -> ActixResult<User, ServiceError> {
     debug!("Attempting login for user: {}", credentials.email);
+    // Sessions the `/users` routes would reject are not started.
+    if !settings.users_auth_mode.accepts(mode) {
+        return Err(ServiceError::BadRequest(format!(
+            "Login with mode {} is not enabled",
+            mode.as_str()
+        )));
+    }
+    credentials.validate()?;
 
     let user_email = credentials.email.clone();
     let password = credentials.password.clone();
 
     // Attempt to find the user by email.
-    let user_data = web::block(move || find_user_by_email(db, &user_email))
-        .await
-        .map_err(|e| ServiceError::from(e))?;
+    let lookup_pool = db.clone();
+    let user_data = telemetry::db_block("find_user_by_email", move || {
+        find_user_by_email(lookup_pool, &user_email)
+    })
+    .await
+    .map_err(ServiceError::from)?;
 
     // If a user is found, verify their password.
     if let Ok(Some(user_data)) = user_data {
-        let verification_result = verify_password(&password, &user_data.user_password);
+        telemetry::record_user(user_data.id);
+        *account = Some(user_data.id);
+        let verification_result = verify_password(&password, &user_data.user_password).await;
 
-        // If password verification is successful, request an Auth0 token.
+        // If password verification is successful, the user may start a session.
         match verification_result {
             Ok(true) => {
-                // Fetch the JWT token from Auth0
-                match request_auth0_token().await {
-                    Ok(auth0_response) => {
-                        // Send the Auth0 token back to the user
-                        // You might want to create a new type for this response
-                        info!("Auth0 token received for user: {}", &credentials.email);
-                        Ok(HttpResponse::Ok().json(auth0_response))
-                    }
-                    Err(e) => {
-                        // Handle the error, possibly returning a ServiceError
-                        error!("Error fetching token from Auth0: {:?}", e);
-                        Err(ServiceError::JWKSFetchError)
-                    }
+                // Accounts locked or flagged for a password reset by an admin cannot log in.
+                check_account_status(&user_data, &credentials.email)?;
+
+                // Upgrade hashes created with outdated Argon2 parameters.
+                if needs_rehash(&user_data.user_password) {
+                    rehash_password(db, user_data.id, &user_data.user_password, &password).await;
                 }
+                Ok(user_data)
             }
             Ok(false) => {
                 warn!(
@@ -166,11 +294,708 @@ pub async fn login(
             }
         }
     } else {
+        // Answer as for a wrong password, after as much work, so the account's existence is not revealed.
+        verify_dummy_password(&password).await;
         warn!(
             "Login failed for user: {}, user not found.",
             &credentials.email
         );
-        Err(ServiceError::NotFound)
+        Err(ServiceError::Unauthorized)
+    }
+}
+
+/// Requests an Auth0 token for an authenticated user and records it as a new session.
+///
+/// Tokens carry no user identity, so a token already recorded for another session is never handed out:
+/// Auth0 issues identical tokens to logins within the same second, in which case a fresh token is
+/// requested once the second has passed.
+async fn issue_token(
+    db: web::Data<Pool>,              // Database connection pool
+    auth0: web::Data<Auth0Settings>,  // Auth0 client settings
+    history: web::Data<LoginHistory>, // Login history recorder
+    user: &User,                      // User logging in
+    context: &AuditContext,           // Client details for the login history
+) -> ActixResult<HttpResponse, ServiceError> {
+    // Add the login to the user's history, which holds the device of the session.
+    let login_id = login_history::record(history, db.clone(), user, context).await;
+
+    for attempt in 1..=TOKEN_ATTEMPTS {
+        // Fetch the JWT token from Auth0
+        let auth0_response = match request_auth0_token(&auth0).await {
+            Ok(auth0_response) => auth0_response,
+            Err(e) => {
+                // Handle the error, possibly returning a ServiceError
+                error!("Error fetching token from Auth0: {:?}", e);
+                return Err(ServiceError::JWKSFetchError);
+            }
+        };
+        info!("Auth0 token received for user id: {}", user.id);
+
+        // Record the token so it stops working if the account is locked later.
+        let token = auth0_response.access_token.clone();
+        let lifetime = sessions::token_lifetime(auth0_response.expires_in);
+        let user_id = user.id;
+        let record_pool = db.clone();
+        let recorded = telemetry::db_block("record_session", move || {
+            let mut conn = record_pool.get().map_err(ServiceError::Pool)?;
+            match sessions::record_session(
+                &mut conn,
+                user_id,
+                SessionKind::Bearer,
+                &token,
+                lifetime,
+                login_id,
+            ) {
+                Ok(()) => Ok(true),
+                Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Ok(false),
+                Err(e) => Err(ServiceError::Diesel(e)),
+            }
+        })
+        .await??;
+
+        if recorded {
+            // Send the Auth0 token back to the user
+            // You might want to create a new type for this response
+            return Ok(HttpResponse::Ok().json(auth0_response));
+        }
+        warn!(
+            "Auth0 issued a token already recorded for another session (attempt {} of {})",
+            attempt, TOKEN_ATTEMPTS
+        );
+        if attempt < TOKEN_ATTEMPTS {
+            actix_rt::time::sleep(TOKEN_RETRY_DELAY).await;
+        }
+    }
+
+    error!("Auth0 kept issuing tokens already recorded for other sessions");
+    Err(ServiceError::JWKSFetchError)
+}
+
+/// Starts a cookie session for an authenticated user, setting the session and CSRF cookies.
+///
+/// The CSRF token is also returned in the body, along with the number of seconds the session lasts
+/// without requests.
+async fn start_cookie_session(
+    db: web::Data<Pool>,              // Database connection pool
+    policy: CookiePolicy,             // Cookie attributes and session lifetimes
+    history: web::Data<LoginHistory>, // Login history recorder
+    user: &User,                      // User logging in
+    context: &AuditContext,           // Client details for the login history
+) -> ActixResult<HttpResponse, ServiceError> {
+    // Add the login to the user's history, which holds the device of the session.
+    let login_id = login_history::record(history, db.clone(), user, context).await;
+
+    let session_id = cookie_sessions::generate_session_id();
+    let now = chrono::Local::now().naive_local();
+    let expires_at = policy.expiry(now, now);
+    let secret = session_id.clone();
+    let user_id = user.id;
+    telemetry::db_block("record_session", move || {
+        let mut conn = db.get().map_err(ServiceError::Pool)?;
+        sessions::record_session(
+            &mut conn,
+            user_id,
+            SessionKind::Cookie,
+            &secret,
+            expires_at - now,
+            login_id,
+        )
+        .map_err(ServiceError::Diesel)
+    })
+    .await??;
+    info!("Cookie session started for user: {}", &user.email);
+
+    let mut response = HttpResponse::Ok();
+    for cookie in policy.session_cookies(&session_id, expires_at, now) {
+        response.cookie(cookie);
+    }
+    Ok(response.json(serde_json::json!({
+        "csrf_token": cookie_sessions::csrf_token(&session_id),
+        "expires_in": (expires_at - now).num_seconds(),
+    })))
+}
+
+/// Handler for changing the signed-in user's password.
+///
+/// This asynchronous function verifies the user's current password, checks the new password
+/// against the password policy, and stores the hash of the new password.
+///
+/// # Arguments
+///
+/// * `db`: Database connection pool.
+/// * `policy`: Password policy applied to the new password.
+/// * `user`: The signed-in user.
+/// * `change`: The user's current password and new password.
+/// * `context`: Client the request came from, for the audit log.
+///
+/// # Returns
+///
+/// This function returns an Actix result with either an empty HTTP response or a ServiceError.
+pub async fn change_password(
+    db: web::Data<Pool>,               // Database connection pool
+    policy: web::Data<PasswordPolicy>, // Password policy
+    user: AuthenticatedUser,           // Signed-in user
+    change: web::Json<PasswordChange>, // Password change request
+    context: AuditContext,             // Client details for the audit log
+) -> ActixResult<HttpResponse, ServiceError> {
+    debug!("Attempting password change for user id: {}", user.user_id);
+
+    let change = change.into_inner();
+    change.validate()?;
+
+    let user_id = user.user_id;
+    let lookup_pool = db.clone();
+    let user = telemetry::db_block("find_user", move || {
+        let mut conn = lookup_pool.get().map_err(ServiceError::Pool)?;
+        users
+            .filter(id.eq(user_id))
+            .filter(deleted_at.is_null())
+            .first::<User>(&mut conn)
+            .map_err(ServiceError::Diesel)
+    })
+    .await??;
+
+    set_password(
+        db,
+        policy,
+        &user,
+        &change.current_password,
+        &change.new_password,
+        &context,
+    )
+    .await
+}
+
+/// Handler for setting a new password after an admin forced a password reset.
+///
+/// Users flagged for a reset have been signed out and cannot log in, and their current password may be
+/// known to an attacker, so they identify themselves with their email and the one-time token emailed to
+/// them when the reset was forced. Unknown emails, wrong or expired tokens and accounts that are not
+/// flagged are all rejected with the same `401 Unauthorized`, so the route does not reveal which accounts
+/// exist.
+///
+/// # Arguments
+///
+/// * `db`: Database connection pool.
+/// * `policy`: Password policy applied to the new password.
+/// * `reset`: The user's email, reset token and new password.
+/// * `context`: Client the request came from, for the audit log.
+///
+/// # Returns
+///
+/// This function returns an Actix result with either an empty HTTP response or a ServiceError.
+pub async fn reset_password(
+    db: web::Data<Pool>,               // Database connection pool
+    policy: web::Data<PasswordPolicy>, // Password policy
+    reset: web::Json<PasswordReset>,   // Password reset request
+    context: AuditContext,             // Client details for the audit log
+) -> ActixResult<HttpResponse, ServiceError> {
+    let reset = reset.into_inner();
+    reset.validate()?;
+
+    let user_email = reset.email.clone();
+    let lookup_pool = db.clone();
+    let user = match telemetry::db_block("find_user_by_email", move || {
+        find_user_by_email(lookup_pool, &user_email)
+    })
+    .await?
+    {
+        Ok(Some(user)) if user.password_reset_required => user,
+        Ok(_) | Err(ServiceError::NotFound) => {
+            warn!("Password reset rejected, no account flagged for a reset with that email.");
+            return Err(ServiceError::Unauthorized);
+        }
+        Err(e) => return Err(e),
+    };
+    telemetry::record_user(user.id);
+
+    if !password_resets::token_matches(&user, &reset.token) {
+        warn!(
+            "Password reset failed for user id: {}, invalid or expired token.",
+            user.id
+        );
+        let event = Event::failure(EventType::PasswordChange)
+            .by_user(user.id)
+            .target(user.id)
+            .detail("reason", "invalid_reset_token");
+        audit::record(db, &context, event).await;
+        return Err(ServiceError::Unauthorized);
+    }
+
+    let token_hash = sessions::token_hash(&reset.token);
+    store_password(
+        db,
+        policy,
+        &user,
+        &reset.new_password,
+        Some(token_hash),
+        &context,
+    )
+    .await
+}
+
+/// Verifies a user's current password and replaces it with a new one that satisfies the password policy.
+///
+/// Both outcomes are recorded in the audit log as password changes.
+async fn set_password(
+    db: web::Data<Pool>,               // Database connection pool
+    policy: web::Data<PasswordPolicy>, // Password policy
+    user: &User,                       // User changing their password
+    current_password: &str,            // Password the user claims to have
+    new_password: &str,                // Password to store
+    context: &AuditContext,            // Client details for the audit log
+) -> ActixResult<HttpResponse, ServiceError> {
+    match verify_password(current_password, &user.user_password).await {
+        Ok(true) => {}
+        Ok(false) | Err(_) => {
+            warn!(
+                "Password change failed for user id: {}, invalid credentials.",
+                user.id
+            );
+            let event = Event::failure(EventType::PasswordChange)
+                .by_user(user.id)
+                .target(user.id)
+                .detail("reason", "invalid_credentials");
+            audit::record(db, context, event).await;
+            return Err(ServiceError::Unauthorized);
+        }
+    }
+    store_password(db, policy, user, new_password, None, context).await
+}
+
+/// Replaces a user's password with a new one that satisfies the password policy.
+///
+/// Storing the new password also satisfies a pending forced reset and clears its token. When
+/// `reset_token_hash` is given, the password is only stored while that token is still the user's, so a
+/// token is used once even by concurrent requests.
+async fn store_password(
+    db: web::Data<Pool>,               // Database connection pool
+    policy: web::Data<PasswordPolicy>, // Password policy
+    user: &User,                       // User changing their password
+    new_password: &str,                // Password to store
+    reset_token_hash: Option<String>,  // Hash of the reset token the user proved themselves with
+    context: &AuditContext,            // Client details for the audit log
+) -> ActixResult<HttpResponse, ServiceError> {
+    if let Err(e) = user.check_not_locked() {
+        warn!("Password change rejected for user id: {}, {}.", user.id, e);
+        return Err(e);
+    }
+
+    // Check the new password against the password policy.
+    let candidate = new_password.to_string();
+    web::block(move || policy.check(&candidate)).await??;
+
+    let hashed_password = hash_password(new_password)
+        .await
+        .map_err(|_| ServiceError::BadRequest("Password hashing failed".to_string()))?;
+
+    // Store the new password hash, which also satisfies a pending forced reset.
+    let user_id = user.id;
+    let update_pool = db.clone();
+    let updated = telemetry::db_block("update_password", move || {
+        let mut conn = update_pool.get().map_err(ServiceError::Pool)?;
+        let changes = (
+            user_password.eq(hashed_password),
+            password_reset_required.eq(false),
+            password_reset_token_hash.eq(None::<String>),
+            password_reset_expires_at.eq(None::<chrono::NaiveDateTime>),
+        );
+        match reset_token_hash {
+            Some(token_hash) => diesel::update(
+                users
+                    .filter(id.eq(user_id))
+                    .filter(password_reset_token_hash.eq(token_hash))
+                    .filter(password_reset_expires_at.gt(chrono::Local::now().naive_local())),
+            )
+            .set(changes)
+            .execute(&mut conn),
+            None => diesel::update(users.filter(id.eq(user_id)))
+                .set(changes)
+                .execute(&mut conn),
+        }
+        .map_err(ServiceError::Diesel)
+    })
+    .await??;
+    if updated == 0 {
+        warn!(
+            "Password reset failed for user id: {}, token already used.",
+            user_id
+        );
+        return Err(ServiceError::Unauthorized);
+    }
+
+    info!("Password changed for user id: {}", user_id);
+    let event = Event::success(EventType::PasswordChange)
+        .by_user(user_id)
+        .target(user_id);
+    audit::record(db, context, event).await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Handler for deleting the signed-in user's account.
+///
+/// The account is soft-deleted and all of its sessions are revoked, so this and every other token
+/// issued to the user stops working. Personal data is erased after the retention period by the
+/// erasure job; until then an admin can restore the account.
+///
+/// # Arguments
+///
+/// * `db`: Database connection pool.
+/// * `user`: The signed-in user.
+/// * `context`: Client the request came from, for the audit log.
+///
+/// # Returns
+///
+/// This function returns an Actix result with an empty response or a ServiceError.
+pub async fn delete_account(
+    db: web::Data<Pool>,     // Database connection pool
+    user: AuthenticatedUser, // Signed-in user
+    context: AuditContext,   // Client details for the audit log
+) -> ActixResult<HttpResponse, ServiceError> {
+    let user_id = user.user_id;
+    let delete_pool = db.clone();
+    let deleted = telemetry::db_block("soft_delete_user", move || {
+        let mut conn = delete_pool.get().map_err(ServiceError::Pool)?;
+        erasure::soft_delete_user(&mut conn, user_id).map_err(ServiceError::Diesel)
+    })
+    .await??;
+
+    if !deleted {
+        return Err(ServiceError::NotFound);
+    }
+    info!("User id {} deleted their account", user_id);
+    let event = Event::success(EventType::AccountDeletion)
+        .by_user(user_id)
+        .target(user_id);
+    audit::record(db, &context, event).await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Handler for signing out of the session the request belongs to.
+///
+/// The session's token or cookie stops working immediately, and the cookies of a cookie session are
+/// removed from the browser.
+///
+/// # Arguments
+///
+/// * `db`: Database connection pool.
+/// * `settings`: Application settings, with the attributes of the session cookies.
+/// * `user`: The signed-in user.
+/// * `context`: Client the request came from, for the audit log.
+///
+/// # Returns
+///
+/// This function returns an Actix result with an empty response or a ServiceError.
+pub async fn logout(
+    db: web::Data<Pool>,           // Database connection pool
+    settings: web::Data<Settings>, // Application settings
+    user: AuthenticatedUser,       // Signed-in user
+    context: AuditContext,         // Client details for the audit log
+) -> ActixResult<HttpResponse, ServiceError> {
+    let user_id = user.user_id;
+    let session_id = user.session_id;
+    let revoke_pool = db.clone();
+    telemetry::db_block("revoke_session", move || {
+        let mut conn = revoke_pool.get().map_err(ServiceError::Pool)?;
+        sessions::revoke_session(&mut conn, user_id, session_id).map_err(ServiceError::Diesel)
+    })
+    .await??;
+
+    info!(
+        "User id {} signed out of session id {}",
+        user_id, session_id
+    );
+    let event = Event::success(EventType::TokenRevocation)
+        .by_user(user_id)
+        .target(user_id)
+        .detail("session_id", session_id)
+        .detail("current", true);
+    audit::record(db, &context, event).await;
+
+    let mut response = HttpResponse::NoContent();
+    if user.kind == SessionKind::Cookie {
+        for cookie in settings.cookie_sessions.removal_cookies() {
+            response.cookie(cookie);
+        }
+    }
+    Ok(response.finish())
+}
+
+/// Handler for exporting the signed-in user's personal data.
+///
+/// Returns a JSON document, served as a file download, with everything the service stores about
+/// the user: their profile, the sessions issued to them, their login history and the audit events
+/// concerning them.
+/// Password and token hashes are excluded.
+///
+/// # Arguments
+///
+/// * `db`: Database connection pool.
+/// * `user`: The signed-in user.
+///
+/// # Returns
+///
+/// This function returns an Actix result with the export or a ServiceError.
+pub async fn export_account(
+    db: web::Data<Pool>,     // Database connection pool
+    user: AuthenticatedUser, // Signed-in user
+) -> ActixResult<HttpResponse, ServiceError> {
+    let user_id = user.user_id;
+    let export = telemetry::db_block("export_user_data", move || {
+        let mut conn = db.get().map_err(ServiceError::Pool)?;
+        data_export::export_user_data(&mut conn, user_id).map_err(ServiceError::Diesel)
+    })
+    .await??
+    .ok_or(ServiceError::NotFound)?;
+
+    info!("User id {} exported their personal data", user_id);
+    Ok(HttpResponse::Ok()
+        .insert_header((
+            "Content-Disposition",
+            "attachment; filename=\"personal-data.json\"",
+        ))
+        .json(export))
+}
+
+/// An active session as listed to its user.
+#[derive(Debug, Serialize)]
+pub struct SessionView {
+    #[serde(flatten)]
+    pub session: sessions::ActiveSession,
+    pub current: bool, // Whether this is the session of the request's token.
+}
+
+/// Handler for listing the signed-in user's active sessions.
+///
+/// Returns the sessions that are neither revoked nor expired, most recent first, with the device
+/// and location of the login that created each one. The session of the request's own token is
+/// marked as current.
+///
+/// # Arguments
+///
+/// * `db`: Database connection pool.
+/// * `user`: The signed-in user.
+///
+/// # Returns
+///
+/// This function returns an Actix result with the sessions or a ServiceError.
+pub async fn list_sessions(
+    db: web::Data<Pool>,     // Database connection pool
+    user: AuthenticatedUser, // Signed-in user
+) -> ActixResult<HttpResponse, ServiceError> {
+    let user_id = user.user_id;
+    let active = telemetry::db_block("list_sessions", move || {
+        let mut conn = db.get().map_err(ServiceError::Pool)?;
+        sessions::active_sessions(&mut conn, user_id).map_err(ServiceError::Diesel)
+    })
+    .await??;
+
+    let views: Vec<SessionView> = active
+        .into_iter()
+        .map(|session| SessionView {
+            current: session.id == user.session_id,
+            session,
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(views))
+}
+
+/// Handler for signing the signed-in user out of one of their sessions.
+///
+/// The session's token stops working immediately. Revoking the current session signs out the
+/// device making the request.
+///
+/// # Arguments
+///
+/// * `db`: Database connection pool.
+/// * `user`: The signed-in user.
+/// * `path`: The id of the session.
+/// * `context`: Client the request came from, for the audit log.
+///
+/// # Returns
+///
+/// This function returns an Actix result with an empty response or a ServiceError.
+pub async fn revoke_session(
+    db: web::Data<Pool>,     // Database connection pool
+    user: AuthenticatedUser, // Signed-in user
+    path: web::Path<i32>,    // Session id
+    context: AuditContext,   // Client details for the audit log
+) -> ActixResult<HttpResponse, ServiceError> {
+    let user_id = user.user_id;
+    let session_id = path.into_inner();
+    let revoke_pool = db.clone();
+    let revoked = telemetry::db_block("revoke_session", move || {
+        let mut conn = revoke_pool.get().map_err(ServiceError::Pool)?;
+        sessions::revoke_session(&mut conn, user_id, session_id).map_err(ServiceError::Diesel)
+    })
+    .await??;
+
+    if !revoked {
+        return Err(ServiceError::NotFound);
+    }
+    info!("User id {} revoked session id {}", user_id, session_id);
+    let event = Event::success(EventType::TokenRevocation)
+        .by_user(user_id)
+        .target(user_id)
+        .detail("session_id", session_id)
+        .detail("current", session_id == user.session_id);
+    audit::record(db, &context, event).await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Handler for signing the signed-in user out everywhere except the device making the request.
+///
+/// # Arguments
+///
+/// * `db`: Database connection pool.
+/// * `user`: The signed-in user.
+/// * `context`: Client the request came from, for the audit log.
+///
+/// # Returns
+///
+/// This function returns an Actix result with the number of revoked sessions or a ServiceError.
+pub async fn revoke_other_sessions(
+    db: web::Data<Pool>,     // Database connection pool
+    user: AuthenticatedUser, // Signed-in user
+    context: AuditContext,   // Client details for the audit log
+) -> ActixResult<HttpResponse, ServiceError> {
+    let user_id = user.user_id;
+    let session_id = user.session_id;
+    let revoke_pool = db.clone();
+    let revoked = telemetry::db_block("revoke_other_sessions", move || {
+        let mut conn = revoke_pool.get().map_err(ServiceError::Pool)?;
+        sessions::revoke_other_sessions(&mut conn, user_id, session_id)
+            .map_err(ServiceError::Diesel)
+    })
+    .await??;
+
+    info!("User id {} revoked {} other sessions", user_id, revoked);
+    let event = Event::success(EventType::TokenRevocation)
+        .by_user(user_id)
+        .target(user_id)
+        .detail("revoked_sessions", revoked)
+        .detail("kept_session_id", session_id);
+    audit::record(db, &context, event).await;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "revoked": revoked })))
+}
+
+/// Query parameters of the login history.
+#[derive(Debug, Deserialize)]
+pub struct LoginHistoryQuery {
+    pub limit: Option<i64>, // Number of logins, up to `MAX_LOGIN_HISTORY`.
+}
+
+// Number of logins returned when none is requested, and the most returned at once.
+const DEFAULT_LOGIN_HISTORY: i64 = 20;
+const MAX_LOGIN_HISTORY: i64 = 100;
+
+/// Handler for listing the signed-in user's recent logins.
+///
+/// Returns the most recent successful logins, newest first, with the IP address, device and
+/// approximate location of each.
+///
+/// # Arguments
+///
+/// * `db`: Database connection pool.
+/// * `user`: The signed-in user.
+/// * `query`: Number of logins to return.
+///
+/// # Returns
+///
+/// This function returns an Actix result with the logins or a ServiceError.
+pub async fn list_logins(
+    db: web::Data<Pool>,                  // Database connection pool
+    user: AuthenticatedUser,              // Signed-in user
+    query: web::Query<LoginHistoryQuery>, // Listing options
+) -> ActixResult<HttpResponse, ServiceError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LOGIN_HISTORY)
+        .clamp(1, MAX_LOGIN_HISTORY);
+    let user_id = user.user_id;
+    let logins = telemetry::db_block("list_logins", move || {
+        let mut conn = db.get().map_err(ServiceError::Pool)?;
+        login_history::recent_logins(&mut conn, user_id, limit).map_err(ServiceError::Diesel)
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(logins))
+}
+
+/// Utility function to check that a user whose password was just verified may log in.
+///
+/// # Arguments
+///
+/// * `user`: The authenticated user.
+/// * `user_email`: The email the user logged in with, for logging.
+///
+/// # Returns
+///
+/// This function returns an error if the account is disabled, suspended or requires a password change.
+fn check_account_status(user: &User, user_email: &str) -> Result<(), ServiceError> {
+    if let Err(e) = user.check_not_locked() {
+        warn!("Login rejected for user: {}, {}.", user_email, e);
+        return Err(e);
+    }
+    if user.password_reset_required {
+        warn!(
+            "Login rejected for user: {}, password reset required.",
+            user_email
+        );
+        return Err(ServiceError::PasswordResetRequired);
+    }
+    Ok(())
+}
+
+/// Utility function to rehash a user's password with the current Argon2 parameters.
+///
+/// This is called after a successful login, while the plaintext password is known. Failures
+/// are logged but do not affect the login, since the old hash remains valid. The hash is only
+/// replaced if it is still the one that was verified, so a password changed in the meantime is kept.
+///
+/// # Arguments
+///
+/// * `pool`: Database connection pool.
+/// * `user_id`: The id of the user whose password is rehashed.
+/// * `verified_hash`: The stored hash the password was verified against.
+/// * `password`: The verified plaintext password.
+async fn rehash_password(pool: web::Data<Pool>, user_id: i32, verified_hash: &str, password: &str) {
+    let new_hash = match hash_password(password).await {
+        Ok(new_hash) => new_hash,
+        Err(e) => {
+            error!("Failed to rehash password for user id {}: {:?}", user_id, e);
+            return;
+        }
+    };
+
+    let verified_hash = verified_hash.to_string();
+    let update_result = telemetry::db_block("rehash_password", move || {
+        let mut conn = pool.get().map_err(ServiceError::Pool)?;
+        diesel::update(
+            users
+                .filter(id.eq(user_id))
+                .filter(user_password.eq(verified_hash)),
+        )
+        .set(user_password.eq(new_hash))
+        .execute(&mut conn)
+        .map_err(ServiceError::Diesel)
+    })
+    .await;
+
+    match update_result {
+        Ok(Ok(0)) => info!(
+            "Password of user id {} changed before it was rehashed, keeping the new one",
+            user_id
+        ),
+        Ok(Ok(_)) => info!("Rehashed password for user id {}", user_id),
+        Ok(Err(e)) => error!(
+            "Failed to store rehashed password for user id {}: {:?}",
+            user_id, e
+        ),
+        Err(e) => error!(
+            "Failed to store rehashed password for user id {}: {:?}",
+            user_id, e
+        ),
     }
 }
 
@@ -191,11 +1016,22 @@ fn find_user_by_email(
     debug!("Looking for user by email: {}", user_email);
 
     let mut conn = pool.get().map_err(ServiceError::Pool)?;
-    let user = users
-        .filter(email.eq(user_email))
-        .first::<User>(&mut conn)
-        .optional()
-        .map_err(ServiceError::Diesel)?;
+    let user = match normalize_email(user_email) {
+        Ok(normalized_email) => users
+            .filter(email_normalized.eq(normalized_email.key))
+            .filter(deleted_at.is_null())
+            .first::<User>(&mut conn)
+            .optional(),
+        // Addresses that cannot be normalized, such as those of accounts created before addresses were
+        // validated (`user@intranet`), only match an account without an identity key exactly.
+        Err(_) => users
+            .filter(email.eq(user_email.trim()))
+            .filter(email_normalized.is_null())
+            .filter(deleted_at.is_null())
+            .first::<User>(&mut conn)
+            .optional(),
+    }
+    .map_err(ServiceError::Diesel)?;
 
     // If user is None, return NotFound error
     match user {
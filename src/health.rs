@@ -0,0 +1,197 @@
+//! # Health Module
+//!
+//! This module serves the endpoints probed by the orchestrator. `GET /health/live` only reports that the
+//! process is serving requests. `GET /health/ready` checks the dependencies a request needs: a database
+//! connection from the pool, an up to date schema, the JWKS of the Auth0 authority and at least one
+//! signing key in it. It answers `200 OK` when all checks pass and `503 Service Unavailable` otherwise,
+//! with the outcome of each check in the body.
+
+use crate::auth;
+use crate::errors::ServiceError;
+use crate::settings::Auth0Settings;
+use crate::Pool;
+use actix_web::{web, HttpResponse, Result as ActixResult};
+use diesel::prelude::*;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Migrations the running code expects to have been applied.
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+/// Longest a single readiness check may take before it is reported as down.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Outcome of a single readiness check.
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub status: &'static str, // `up` or `down`.
+    pub duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub items: Vec<String>, // Pending migrations, or the ids of the signing keys.
+}
+
+impl Check {
+    fn new(started: Instant, outcome: Result<Vec<String>, String>) -> Self {
+        let duration_ms = started.elapsed().as_millis();
+        match outcome {
+            Ok(items) => Check {
+                status: "up",
+                duration_ms,
+                detail: None,
+                items,
+            },
+            Err(detail) => Check {
+                status: "down",
+                duration_ms,
+                detail: Some(detail),
+                items: Vec::new(),
+            },
+        }
+    }
+
+    fn is_up(&self) -> bool {
+        self.status == "up"
+    }
+}
+
+/// Body of a readiness response.
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub status: &'static str, // `ready` or `unavailable`.
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+/// Handler for the liveness probe.
+///
+/// # Returns
+///
+/// Always `200 OK`; failing dependencies do not make the process unhealthy.
+pub async fn live() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Handler for the readiness probe.
+///
+/// # Arguments
+///
+/// * `db`: Database connection pool.
+/// * `auth0`: Auth0 settings naming the authority whose JWKS is checked.
+///
+/// # Returns
+///
+/// `200 OK` when every dependency is available, `503 Service Unavailable` otherwise, with the outcome
+/// of each check in the body.
+pub async fn ready(
+    db: web::Data<Pool>,             // Database connection pool
+    auth0: web::Data<Auth0Settings>, // Auth0 authority settings
+) -> ActixResult<HttpResponse, ServiceError> {
+    let (database, jwks) = futures::join!(check_database(db), check_jwks(&auth0.authority));
+    let (database, migrations) = database;
+    let (jwks, signing_keys) = jwks;
+
+    let checks = BTreeMap::from([
+        ("database", database),
+        ("migrations", migrations),
+        ("jwks", jwks),
+        ("signing_keys", signing_keys),
+    ]);
+    let failed: Vec<&str> = checks
+        .iter()
+        .filter(|(_, check)| !check.is_up())
+        .map(|(name, _)| *name)
+        .collect();
+
+    if failed.is_empty() {
+        Ok(HttpResponse::Ok().json(Readiness {
+            status: "ready",
+            checks,
+        }))
+    } else {
+        warn!("Readiness check failed for: {}", failed.join(", "));
+        Ok(HttpResponse::ServiceUnavailable().json(Readiness {
+            status: "unavailable",
+            checks,
+        }))
+    }
+}
+
+// Checks that a pooled connection answers queries and that no migrations are pending.
+async fn check_database(db: web::Data<Pool>) -> (Check, Check) {
+    let started = Instant::now();
+    let outcome = web::block(move || {
+        let mut conn = db.get_timeout(CHECK_TIMEOUT).map_err(|e| e.to_string())?;
+        diesel::sql_query("SELECT 1")
+            .execute(&mut conn)
+            .map_err(|e| e.to_string())?;
+        let connected = Instant::now();
+
+        let pending = conn
+            .pending_migrations(MIGRATIONS)
+            .map(|pending| {
+                pending
+                    .iter()
+                    .map(|m| m.name().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .map_err(|e| e.to_string());
+        Ok::<_, String>((connected, pending))
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|outcome| outcome);
+
+    match outcome {
+        Ok((connected, pending)) => {
+            let database = Check::new(started, Ok(Vec::new()));
+            let migrations = match pending {
+                Ok(pending) if pending.is_empty() => Check::new(connected, Ok(pending)),
+                Ok(pending) => {
+                    let detail = format!("{} migrations are pending", pending.len());
+                    Check {
+                        items: pending,
+                        ..Check::new(connected, Err(detail))
+                    }
+                }
+                Err(e) => Check::new(connected, Err(e)),
+            };
+            (database, migrations)
+        }
+        Err(e) => (
+            Check::new(started, Err(e)),
+            Check::new(started, Err("database unavailable".to_string())),
+        ),
+    }
+}
+
+// Checks that the authority's JWKS can be fetched and that it holds at least one signing key.
+async fn check_jwks(authority: &str) -> (Check, Check) {
+    let started = Instant::now();
+    let outcome = actix_rt::time::timeout(CHECK_TIMEOUT, auth::jwks_signing_keys(authority)).await;
+
+    match outcome {
+        Ok(Ok(key_ids)) => {
+            let signing_keys = if key_ids.is_empty() {
+                Err("the JWKS holds no signing keys".to_string())
+            } else {
+                Ok(key_ids)
+            };
+            (
+                Check::new(started, Ok(Vec::new())),
+                Check::new(started, signing_keys),
+            )
+        }
+        Ok(Err(e)) => (
+            Check::new(started, Err(e.to_string())),
+            Check::new(started, Err("JWKS unavailable".to_string())),
+        ),
+        Err(_) => (
+            Check::new(started, Err("timed out fetching the JWKS".to_string())),
+            Check::new(started, Err("JWKS unavailable".to_string())),
+        ),
+    }
+}
@@ -0,0 +1,425 @@
+//! # Legacy Hashes Module
+//!
+//! This module verifies password hashes imported from other applications, so their users can sign in
+//! before their hashes are upgraded to Argon2 on first successful login. Formats are recognised by their
+//! algorithm prefix:
+//!     - bcrypt: `$2a$`, `$2b$`, `$2x$`, `$2y$` (Modular Crypt Format).
+//!     - Passlib: `$pbkdf2-sha256$<rounds>$<salt>$<hash>` and `$scrypt$ln=<n>,r=<r>,p=<p>$<salt>$<hash>`,
+//!       with salt and hash in Passlib's adapted base64.
+//!     - Django: `pbkdf2_sha256$<iterations>$<salt>$<hash>`, `scrypt$<salt>$<n>$<r>$<p>$<hash>`,
+//!       `bcrypt$<bcrypt hash>` and `bcrypt_sha256$<bcrypt hash>`.
+//!
+//! Legacy hashes are never peppered, and new hashes are never created in these formats. Hashes with
+//! cost parameters above this module's limits (bcrypt cost 16, 2,000,000 PBKDF2 iterations, 128 MiB
+//! of scrypt memory and parallelism 4) are rejected as malformed.
+
+use crate::errors::PasswordError;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+// Highest cost parameters accepted in legacy hashes. Hashes above them are treated as malformed, since
+// verifying them would tie up a blocking thread for seconds or exhaust memory on every login attempt.
+// The limits are well above the defaults of Django and Passlib, whose hashes remain accepted.
+const MIN_BCRYPT_COST: u32 = 4;
+const MAX_BCRYPT_COST: u32 = 16;
+const MAX_PBKDF2_ROUNDS: u32 = 2_000_000;
+const MAX_SCRYPT_MEMORY_BYTES: u64 = 128 * 1024 * 1024;
+const MAX_SCRYPT_PARALLELISM: u32 = 4;
+
+/// A supported legacy hash format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LegacyFormat {
+    Bcrypt,
+    PasslibPbkdf2Sha256,
+    PasslibScrypt,
+    DjangoPbkdf2Sha256,
+    DjangoScrypt,
+    DjangoBcrypt,
+    DjangoBcryptSha256,
+}
+
+impl LegacyFormat {
+    /// Detects the legacy format of a stored hash from its prefix.
+    ///
+    /// Returns `None` for Argon2 hashes and anything unrecognised.
+    pub fn detect(hash: &str) -> Option<Self> {
+        if ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            Some(LegacyFormat::Bcrypt)
+        } else if hash.starts_with("$pbkdf2-sha256$") {
+            Some(LegacyFormat::PasslibPbkdf2Sha256)
+        } else if hash.starts_with("$scrypt$") {
+            Some(LegacyFormat::PasslibScrypt)
+        } else if hash.starts_with("pbkdf2_sha256$") {
+            Some(LegacyFormat::DjangoPbkdf2Sha256)
+        } else if hash.starts_with("scrypt$") {
+            Some(LegacyFormat::DjangoScrypt)
+        } else if hash.starts_with("bcrypt$") {
+            Some(LegacyFormat::DjangoBcrypt)
+        } else if hash.starts_with("bcrypt_sha256$") {
+            Some(LegacyFormat::DjangoBcryptSha256)
+        } else {
+            None
+        }
+    }
+
+    /// Name of the format used in error messages and logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            LegacyFormat::Bcrypt => "bcrypt",
+            LegacyFormat::PasslibPbkdf2Sha256 => "Passlib PBKDF2-SHA256",
+            LegacyFormat::PasslibScrypt => "Passlib scrypt",
+            LegacyFormat::DjangoPbkdf2Sha256 => "Django PBKDF2-SHA256",
+            LegacyFormat::DjangoScrypt => "Django scrypt",
+            LegacyFormat::DjangoBcrypt => "Django bcrypt",
+            LegacyFormat::DjangoBcryptSha256 => "Django bcrypt_sha256",
+        }
+    }
+
+    /// Verifies a password against a hash in this format.
+    pub fn verify(&self, password: &str, hash: &str) -> Result<bool, PasswordError> {
+        match self.parse(hash)? {
+            ParsedHash::Bcrypt { hash, prehash } => {
+                if prehash {
+                    // Django pre-hashes the password so bcrypt's 72 byte limit does not truncate it.
+                    let prehashed = hex::encode(Sha256::digest(password.as_bytes()));
+                    Ok(bcrypt::verify(prehashed, hash)?)
+                } else {
+                    Ok(bcrypt::verify(password, hash)?)
+                }
+            }
+            ParsedHash::Pbkdf2Sha256 {
+                rounds,
+                salt,
+                expected,
+            } => {
+                let mut derived = vec![0u8; expected.len()];
+                pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, rounds, &mut derived);
+                Ok(derived.ct_eq(&expected).into())
+            }
+            ParsedHash::Scrypt {
+                params,
+                salt,
+                expected,
+            } => {
+                let mut derived = vec![0u8; expected.len()];
+                scrypt::scrypt(password.as_bytes(), &salt, &params, &mut derived)
+                    .map_err(|_| PasswordError::MalformedHash(self.name()))?;
+                Ok(derived.ct_eq(&expected).into())
+            }
+        }
+    }
+
+    /// Checks that a hash in this format can be verified, without verifying a password.
+    ///
+    /// Hashes whose encoding is invalid or whose cost parameters exceed the limits of this module are
+    /// reported as malformed.
+    pub fn validate(&self, hash: &str) -> Result<(), PasswordError> {
+        self.parse(hash).map(|_| ())
+    }
+
+    // Splits a hash into the inputs of its algorithm, enforcing the cost limits.
+    fn parse<'a>(&self, hash: &'a str) -> Result<ParsedHash<'a>, PasswordError> {
+        let malformed = || PasswordError::MalformedHash(self.name());
+
+        match self {
+            LegacyFormat::Bcrypt => parse_bcrypt(hash, false).ok_or_else(malformed),
+            LegacyFormat::DjangoBcrypt => hash
+                .strip_prefix("bcrypt$")
+                .and_then(|inner| parse_bcrypt(inner, false))
+                .ok_or_else(malformed),
+            LegacyFormat::DjangoBcryptSha256 => hash
+                .strip_prefix("bcrypt_sha256$")
+                .and_then(|inner| parse_bcrypt(inner, true))
+                .ok_or_else(malformed),
+            LegacyFormat::PasslibPbkdf2Sha256 => {
+                let fields: Vec<&str> = hash.split('$').collect();
+                let [_, _, rounds, salt, expected] = fields[..] else {
+                    return Err(malformed());
+                };
+                Ok(ParsedHash::Pbkdf2Sha256 {
+                    rounds: pbkdf2_rounds(rounds).ok_or_else(malformed)?,
+                    salt: decode_passlib_b64(salt).ok_or_else(malformed)?,
+                    expected: decode_passlib_b64(expected).ok_or_else(malformed)?,
+                })
+            }
+            LegacyFormat::DjangoPbkdf2Sha256 => {
+                let fields: Vec<&str> = hash.split('$').collect();
+                let [_, iterations, salt, expected] = fields[..] else {
+                    return Err(malformed());
+                };
+                // Django uses the salt string itself, not a decoded form of it.
+                Ok(ParsedHash::Pbkdf2Sha256 {
+                    rounds: pbkdf2_rounds(iterations).ok_or_else(malformed)?,
+                    salt: salt.as_bytes().to_vec(),
+                    expected: decode_standard_b64(expected).ok_or_else(malformed)?,
+                })
+            }
+            LegacyFormat::PasslibScrypt => {
+                let fields: Vec<&str> = hash.split('$').collect();
+                let [_, _, params, salt, expected] = fields[..] else {
+                    return Err(malformed());
+                };
+                let (mut log_n, mut r, mut p) = (None, None, None);
+                for param in params.split(',') {
+                    match param.split_once('=') {
+                        Some(("ln", value)) => log_n = value.parse().ok(),
+                        Some(("r", value)) => r = value.parse().ok(),
+                        Some(("p", value)) => p = value.parse().ok(),
+                        _ => return Err(malformed()),
+                    }
+                }
+                let expected = decode_passlib_b64(expected).ok_or_else(malformed)?;
+                Ok(ParsedHash::Scrypt {
+                    params: scrypt_params(log_n, r, p, expected.len()).ok_or_else(malformed)?,
+                    salt: decode_passlib_b64(salt).ok_or_else(malformed)?,
+                    expected,
+                })
+            }
+            LegacyFormat::DjangoScrypt => {
+                let fields: Vec<&str> = hash.split('$').collect();
+                let [_, salt, n, r, p, expected] = fields[..] else {
+                    return Err(malformed());
+                };
+                let n: u64 = n.parse().map_err(|_| malformed())?;
+                if !n.is_power_of_two() {
+                    return Err(malformed());
+                }
+                let log_n = u8::try_from(n.trailing_zeros()).ok();
+                let expected = decode_standard_b64(expected).ok_or_else(malformed)?;
+                Ok(ParsedHash::Scrypt {
+                    params: scrypt_params(log_n, r.parse().ok(), p.parse().ok(), expected.len())
+                        .ok_or_else(malformed)?,
+                    salt: salt.as_bytes().to_vec(),
+                    expected,
+                })
+            }
+        }
+    }
+}
+
+// The inputs needed to verify a password against a legacy hash.
+enum ParsedHash<'a> {
+    Bcrypt {
+        hash: &'a str, // The bcrypt hash in Modular Crypt Format.
+        prehash: bool, // Whether the password is hashed with SHA-256 first (Django's bcrypt_sha256).
+    },
+    Pbkdf2Sha256 {
+        rounds: u32,
+        salt: Vec<u8>,
+        expected: Vec<u8>,
+    },
+    Scrypt {
+        params: scrypt::Params,
+        salt: Vec<u8>,
+        expected: Vec<u8>,
+    },
+}
+
+// Checks a bcrypt hash: a known version, a cost within the limit and 53 characters of bcrypt's base64
+// holding the salt and the hash.
+fn parse_bcrypt(hash: &str, prehash: bool) -> Option<ParsedHash<'_>> {
+    let parts: bcrypt::HashParts = hash.parse().ok()?;
+    let encoded = hash.rsplit('$').next()?;
+    let valid_encoding = encoded
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '/');
+    let cost = parts.get_cost();
+    if valid_encoding && (MIN_BCRYPT_COST..=MAX_BCRYPT_COST).contains(&cost) {
+        Some(ParsedHash::Bcrypt { hash, prehash })
+    } else {
+        None
+    }
+}
+
+// Parses a PBKDF2 iteration count, which must be between 1 and the limit.
+fn pbkdf2_rounds(rounds: &str) -> Option<u32> {
+    rounds
+        .parse()
+        .ok()
+        .filter(|rounds| (1..=MAX_PBKDF2_ROUNDS).contains(rounds))
+}
+
+// Builds scrypt parameters from the values of a hash, enforcing the memory and parallelism limits.
+fn scrypt_params(
+    log_n: Option<u8>,
+    r: Option<u32>,
+    p: Option<u32>,
+    len: usize,
+) -> Option<scrypt::Params> {
+    let (log_n, r, p) = (log_n?, r?, p?);
+    // scrypt needs 128 * r * N bytes of memory.
+    if log_n >= 64 {
+        return None;
+    }
+    let memory = (128 * u64::from(r)).checked_mul(1 << log_n)?;
+    if memory > MAX_SCRYPT_MEMORY_BYTES || p > MAX_SCRYPT_PARALLELISM {
+        return None;
+    }
+    scrypt::Params::new(log_n, r, p, len).ok()
+}
+
+// Decodes Passlib's adapted base64, which uses `.` instead of `+` and omits padding.
+// Empty values are rejected, since an empty expected hash would match any password.
+fn decode_passlib_b64(encoded: &str) -> Option<Vec<u8>> {
+    STANDARD_NO_PAD
+        .decode(encoded.replace('.', "+"))
+        .ok()
+        .filter(|decoded| !decoded.is_empty())
+}
+
+// Decodes standard padded base64 as used by Django, rejecting empty values.
+fn decode_standard_b64(encoded: &str) -> Option<Vec<u8>> {
+    STANDARD
+        .decode(encoded)
+        .ok()
+        .filter(|decoded| !decoded.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &str = "legacy password";
+
+    #[test]
+    fn detects_formats_by_prefix() {
+        let cases = [
+            ("$2b$12$abc", Some(LegacyFormat::Bcrypt)),
+            (
+                "$pbkdf2-sha256$1000$a$b",
+                Some(LegacyFormat::PasslibPbkdf2Sha256),
+            ),
+            (
+                "$scrypt$ln=10,r=8,p=1$a$b",
+                Some(LegacyFormat::PasslibScrypt),
+            ),
+            (
+                "pbkdf2_sha256$1000$a$b",
+                Some(LegacyFormat::DjangoPbkdf2Sha256),
+            ),
+            ("scrypt$a$1024$8$1$b", Some(LegacyFormat::DjangoScrypt)),
+            ("bcrypt$$2b$12$abc", Some(LegacyFormat::DjangoBcrypt)),
+            (
+                "bcrypt_sha256$$2b$12$abc",
+                Some(LegacyFormat::DjangoBcryptSha256),
+            ),
+            ("$argon2id$v=19$m=4096,t=192,p=8$a$b", None),
+            ("$pepper-v1$argon2id$v=19$m=4096,t=192,p=8$a$b", None),
+        ];
+        for (hash, expected) in cases {
+            assert_eq!(LegacyFormat::detect(hash), expected, "{}", hash);
+        }
+    }
+
+    #[test]
+    fn verifies_bcrypt() {
+        let hash = "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW";
+        assert!(LegacyFormat::Bcrypt.verify("U*U", hash).unwrap());
+        assert!(!LegacyFormat::Bcrypt.verify("U*V", hash).unwrap());
+    }
+
+    #[test]
+    fn verifies_django_bcrypt_variants() {
+        let bcrypt_hash = bcrypt::hash(PASSWORD, 4).unwrap();
+        let hash = format!("bcrypt${}", bcrypt_hash);
+        assert!(LegacyFormat::DjangoBcrypt.verify(PASSWORD, &hash).unwrap());
+
+        let prehashed = hex::encode(Sha256::digest(PASSWORD.as_bytes()));
+        let hash = format!("bcrypt_sha256${}", bcrypt::hash(prehashed, 4).unwrap());
+        assert!(LegacyFormat::DjangoBcryptSha256
+            .verify(PASSWORD, &hash)
+            .unwrap());
+        assert!(!LegacyFormat::DjangoBcryptSha256
+            .verify("wrong", &hash)
+            .unwrap());
+    }
+
+    #[test]
+    fn verifies_pbkdf2_sha256() {
+        let passlib =
+            "$pbkdf2-sha256$1000$AAECAwQFBgcICQoLDA0ODw$ySUUCEVchXk5SrsGEYEvrVNkMO5aIThOIMEEhXCkxgI";
+        assert!(LegacyFormat::PasslibPbkdf2Sha256
+            .verify(PASSWORD, passlib)
+            .unwrap());
+        assert!(!LegacyFormat::PasslibPbkdf2Sha256
+            .verify("wrong", passlib)
+            .unwrap());
+
+        let django =
+            "pbkdf2_sha256$1000$Zr8cUMC1tBcv5yiqGOKsJa$0y2oYLAoo4U4T/jLO+x6mk5ZLRg5VTMbcm/SXuzUyDE=";
+        assert!(LegacyFormat::DjangoPbkdf2Sha256
+            .verify(PASSWORD, django)
+            .unwrap());
+        assert!(!LegacyFormat::DjangoPbkdf2Sha256
+            .verify("wrong", django)
+            .unwrap());
+    }
+
+    #[test]
+    fn verifies_scrypt() {
+        let passlib =
+            "$scrypt$ln=10,r=8,p=1$ZGVmZ2hpamtsbW5vcHFycw$RahmKvzVF4F.oYkVmpm9us/gC5m/ewlUDE0a96KmUR4";
+        assert!(LegacyFormat::PasslibScrypt
+            .verify(PASSWORD, passlib)
+            .unwrap());
+        assert!(!LegacyFormat::PasslibScrypt
+            .verify("wrong", passlib)
+            .unwrap());
+
+        let django = "scrypt$x9L2qJbV7oNmEwC4tRhZsK$1024$8$1$6sWy6zHU0UI9zGH2rVH2RSDg6YSyMiqwBWcQFELx8ixybIIu7jGtM1dCyI39Ep6xMbI0uwLUtAi9cWcV+4aS9w==";
+        assert!(LegacyFormat::DjangoScrypt.verify(PASSWORD, django).unwrap());
+        assert!(!LegacyFormat::DjangoScrypt.verify("wrong", django).unwrap());
+    }
+
+    #[test]
+    fn rejects_hashes_with_excessive_costs() {
+        let cases = [
+            "$pbkdf2-sha256$4000000000$AAECAwQFBgcICQoLDA0ODw$ySUUCEVchXk5SrsGEYEvrVNkMO5aIThOIMEEhXCkxgI",
+            "pbkdf2_sha256$0$Zr8cUMC1tBcv5yiqGOKsJa$0y2oYLAoo4U4T/jLO+x6mk5ZLRg5VTMbcm/SXuzUyDE=",
+            "$scrypt$ln=40,r=8,p=1$ZGVmZ2hpamtsbW5vcHFycw$RahmKvzVF4F.oYkVmpm9us/gC5m/ewlUDE0a96KmUR4",
+            "$scrypt$ln=10,r=8,p=1000$ZGVmZ2hpamtsbW5vcHFycw$RahmKvzVF4F.oYkVmpm9us/gC5m/ewlUDE0a96KmUR4",
+            "scrypt$x9L2qJbV7oNmEwC4tRhZsK$1024$4294967295$1$6sWy6zHU0UI9zGH2rVH2RSDg6YSyMiqwBWcQFELx8ixybIIu7jGtM1dCyI39Ep6xMbI0uwLUtAi9cWcV+4aS9w==",
+            "$2b$31$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW",
+        ];
+        for hash in cases {
+            let format = LegacyFormat::detect(hash).unwrap();
+            assert!(format.validate(hash).is_err(), "{}", hash);
+            assert!(format.verify(PASSWORD, hash).is_err(), "{}", hash);
+        }
+
+        // Django's and Passlib's defaults stay within the limits.
+        assert!(LegacyFormat::DjangoPbkdf2Sha256
+            .validate("pbkdf2_sha256$1000000$Zr8cUMC1tBcv5yiqGOKsJa$0y2oYLAoo4U4T/jLO+x6mk5ZLRg5VTMbcm/SXuzUyDE=")
+            .is_ok());
+        assert!(LegacyFormat::PasslibScrypt
+            .validate("$scrypt$ln=16,r=8,p=1$ZGVmZ2hpamtsbW5vcHFycw$RahmKvzVF4F.oYkVmpm9us/gC5m/ewlUDE0a96KmUR4")
+            .is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_hashes() {
+        assert!(LegacyFormat::PasslibPbkdf2Sha256
+            .verify(PASSWORD, "$pbkdf2-sha256$notanumber$a$b")
+            .is_err());
+        assert!(LegacyFormat::DjangoScrypt
+            .verify(PASSWORD, "scrypt$salt$1000$8$1$AAAA")
+            .is_err());
+        assert!(LegacyFormat::DjangoPbkdf2Sha256
+            .verify(PASSWORD, "pbkdf2_sha256$1000$salt$")
+            .is_err());
+        for hash in [
+            "$2b$04$abc",
+            "$2b$04$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOe!",
+            "bcrypt$$2b$12$abc",
+            "$scrypt$ln=10,r=8,p=1$not*base64$AAAA",
+        ] {
+            let format = LegacyFormat::detect(hash).unwrap();
+            assert!(format.validate(hash).is_err(), "{}", hash);
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! # Login History Module
+//!
+//! This module records every successful login with the client's IP address and user agent, the browser,
+//! operating system and kind of device parsed from the user agent, and the country and city the address is
+//! located in. Locations come from a local MaxMind GeoIP2 or GeoLite2 database configured with
+//! `GEOIP_DATABASE_PATH`; no external service is queried. When a user logs in from a device their account
+//! has not been used from before, they are notified by email.
+
+use crate::audit::AuditContext;
+use crate::errors::ServiceError;
+use crate::mailer::Mailer;
+use crate::models::{Login, NewLogin, User};
+use crate::schema::logins;
+use crate::settings::Settings;
+use crate::telemetry;
+use crate::Pool;
+use actix_web::web;
+use diesel::dsl::exists;
+use diesel::prelude::*;
+use maxminddb::geoip2;
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use tracing::{error, info};
+
+/// The kind of device a login came from, parsed from its user agent.
+///
+/// Version numbers are left out, so that updating the browser does not make a device look new.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    pub browser: String,
+    pub operating_system: String,
+    pub device_type: String,
+}
+
+impl Device {
+    pub fn from_user_agent(user_agent: Option<&str>) -> Self {
+        let parsed = user_agent.and_then(|agent| woothee::parser::Parser::new().parse(agent));
+        let unknown = woothee::woothee::VALUE_UNKNOWN;
+        Device {
+            browser: parsed.as_ref().map_or(unknown, |p| p.name).to_string(),
+            operating_system: parsed.as_ref().map_or(unknown, |p| p.os).to_string(),
+            device_type: parsed.as_ref().map_or(unknown, |p| p.category).to_string(),
+        }
+    }
+
+    /// Hex-encoded SHA-256 hash identifying the device among a user's logins.
+    pub fn fingerprint(&self) -> String {
+        let key = format!(
+            "{}\n{}\n{}",
+            self.browser, self.operating_system, self.device_type
+        );
+        hex::encode(Sha256::digest(key.as_bytes()))
+    }
+}
+
+/// Coarse location of an IP address.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Location {
+    pub country: Option<String>, // ISO 3166-1 code.
+    pub city: Option<String>,    // English name.
+}
+
+/// Records logins and sends new-device notifications.
+pub struct LoginHistory {
+    geoip: Option<maxminddb::Reader<Vec<u8>>>,
+    mailer: Option<Mailer>,
+}
+
+impl LoginHistory {
+    pub fn from_settings(settings: &Settings) -> std::io::Result<Self> {
+        let geoip = match &settings.geoip_database_path {
+            Some(path) => {
+                let reader = maxminddb::Reader::open_readfile(path).map_err(|e| {
+                    std::io::Error::other(format!(
+                        "cannot open GeoIP database {}: {}",
+                        path.display(),
+                        e
+                    ))
+                })?;
+                info!(
+                    "Locating login addresses with {} ({})",
+                    path.display(),
+                    reader.metadata.database_type
+                );
+                Some(reader)
+            }
+            None => {
+                info!("GEOIP_DATABASE_PATH not set, login locations disabled");
+                None
+            }
+        };
+
+        let mailer = match &settings.mail {
+            Some(mail) => Some(Mailer::from_settings(mail).map_err(std::io::Error::other)?),
+            None => {
+                info!("SMTP_URL not set, new device notifications disabled");
+                None
+            }
+        };
+
+        Ok(LoginHistory { geoip, mailer })
+    }
+
+    /// Looks up the country and city of an address; unknown without a GeoIP database.
+    pub fn locate(&self, ip_address: Option<&str>) -> Location {
+        let (Some(geoip), Some(ip)) = (
+            &self.geoip,
+            ip_address.and_then(|ip| ip.parse::<IpAddr>().ok()),
+        ) else {
+            return Location::default();
+        };
+
+        // City databases also hold the country; country databases simply have no city.
+        match geoip.lookup::<geoip2::City>(ip) {
+            Ok(found) => Location {
+                country: found
+                    .country
+                    .and_then(|country| country.iso_code)
+                    .map(str::to_string),
+                city: found
+                    .city
+                    .and_then(|city| city.names)
+                    .and_then(|names| names.get("en").map(|name| name.to_string())),
+            },
+            Err(_) => Location::default(),
+        }
+    }
+}
+
+/// Stores a login, returning its id and whether it came from a device new to an account that has logged
+/// in before.
+///
+/// A user's first login is not treated as coming from a new device, since there is nothing to compare it to.
+pub fn record_login(conn: &mut PgConnection, login: &NewLogin) -> QueryResult<(i32, bool)> {
+    conn.transaction(|conn| {
+        let user_logins = logins::table.filter(logins::user_id.eq(login.user_id));
+        let has_logins: bool = diesel::select(exists(user_logins)).get_result(conn)?;
+        let known_device: bool = diesel::select(exists(
+            user_logins.filter(logins::device_fingerprint.eq(&login.device_fingerprint)),
+        ))
+        .get_result(conn)?;
+
+        let login_id = diesel::insert_into(logins::table)
+            .values(login)
+            .returning(logins::id)
+            .get_result(conn)?;
+        Ok((login_id, has_logins && !known_device))
+    })
+}
+
+/// Loads a user's most recent logins, newest first.
+pub fn recent_logins(conn: &mut PgConnection, user_id: i32, limit: i64) -> QueryResult<Vec<Login>> {
+    logins::table
+        .filter(logins::user_id.eq(user_id))
+        .order((logins::logged_in_at.desc(), logins::id.desc()))
+        .limit(limit)
+        .select(Login::as_select())
+        .load(conn)
+}
+
+/// Records a successful login and notifies the user if it came from a new device, returning the id of
+/// the login.
+///
+/// Failures are logged but do not affect the login.
+pub async fn record(
+    history: web::Data<LoginHistory>,
+    pool: web::Data<Pool>,
+    user: &User,
+    context: &AuditContext,
+) -> Option<i32> {
+    let device = Device::from_user_agent(context.user_agent.as_deref());
+    let location = history.locate(context.ip_address.as_deref());
+    let login = NewLogin {
+        user_id: user.id,
+        logged_in_at: chrono::Local::now().naive_local(),
+        ip_address: context.ip_address.clone(),
+        user_agent: context.user_agent.clone(),
+        device_fingerprint: device.fingerprint(),
+        browser: device.browser,
+        operating_system: device.operating_system,
+        device_type: device.device_type,
+        country: location.country,
+        city: location.city,
+    };
+
+    let result = telemetry::db_block("record_login", move || {
+        let mut conn = pool.get().map_err(ServiceError::Pool)?;
+        let (login_id, new_device) =
+            record_login(&mut conn, &login).map_err(ServiceError::Diesel)?;
+        Ok::<_, ServiceError>((login_id, new_device, login))
+    })
+    .await;
+
+    match result {
+        Ok(Ok((login_id, new_device, login))) => {
+            if new_device {
+                notify_new_device(history, user.email.clone(), login);
+            }
+            Some(login_id)
+        }
+        Ok(Err(e)) => {
+            error!("Failed to record login of user id {}: {:?}", user.id, e);
+            None
+        }
+        Err(e) => {
+            error!("Failed to record login of user id {}: {:?}", user.id, e);
+            None
+        }
+    }
+}
+
+// Emails the user about a login from a new device, in the background.
+fn notify_new_device(history: web::Data<LoginHistory>, email: String, login: NewLogin) {
+    if history.mailer.is_none() {
+        return;
+    }
+    info!("New device login for user id {}, notifying", login.user_id);
+
+    actix_rt::spawn(async move {
+        let user_id = login.user_id;
+        let result = web::block(move || {
+            let Some(mailer) = &history.mailer else {
+                return Ok(());
+            };
+            mailer.send(
+                &email,
+                "New sign-in to your account",
+                new_device_message(&login),
+            )
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!(
+                "Failed to send new device notification to user id {}: {}",
+                user_id, e
+            ),
+            Err(e) => error!(
+                "Failed to send new device notification to user id {}: {:?}",
+                user_id, e
+            ),
+        }
+    });
+}
+
+// Text of the new device notification.
+fn new_device_message(login: &NewLogin) -> String {
+    let location = match (&login.city, &login.country) {
+        (Some(city), Some(country)) => format!("{}, {}", city, country),
+        (None, Some(country)) => country.clone(),
+        _ => "unknown".to_string(),
+    };
+    format!(
+        "Someone just signed in to your account from a device it has not been used from before.\n\n\
+         Time: {}\n\
+         Device: {} on {} ({})\n\
+         Location: {}\n\
+         IP address: {}\n\n\
+         If this was you, no action is needed. If not, change your password right away.\n",
+        login.logged_in_at.format("%Y-%m-%d %H:%M:%S"),
+        login.browser,
+        login.operating_system,
+        login.device_type,
+        location,
+        login.ip_address.as_deref().unwrap_or("unknown"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIREFOX_115: &str =
+        "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:115.0) Gecko/20100101 Firefox/115.0";
+    const FIREFOX_124: &str =
+        "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:124.0) Gecko/20100101 Firefox/124.0";
+    const SAFARI_IPHONE: &str = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Mobile/15E148 Safari/604.1";
+
+    #[test]
+    fn parses_devices_from_user_agents() {
+        let device = Device::from_user_agent(Some(SAFARI_IPHONE));
+        assert_eq!(device.browser, "Safari");
+        assert_eq!(device.operating_system, "iPhone");
+        assert_eq!(device.device_type, "smartphone");
+
+        let unknown = Device::from_user_agent(None);
+        assert_eq!(unknown.browser, "UNKNOWN");
+        assert_eq!(unknown, Device::from_user_agent(Some("curl-like/1")));
+    }
+
+    #[test]
+    fn fingerprints_ignore_browser_versions() {
+        let old = Device::from_user_agent(Some(FIREFOX_115)).fingerprint();
+        let new = Device::from_user_agent(Some(FIREFOX_124)).fingerprint();
+        let phone = Device::from_user_agent(Some(SAFARI_IPHONE)).fingerprint();
+
+        assert_eq!(old, new);
+        assert_ne!(old, phone);
+    }
+
+    #[test]
+    fn locates_nothing_without_a_database() {
+        let history = LoginHistory {
+            geoip: None,
+            mailer: None,
+        };
+        assert_eq!(history.locate(Some("203.0.113.7")), Location::default());
+    }
+}
@@ -0,0 +1,43 @@
+//! # Mailer Module
+//!
+//! This module sends plain text email notifications through the SMTP server configured with `SMTP_URL`,
+//! from the address configured with `EMAIL_FROM`. Sending blocks until the server has accepted the message,
+//! so it should be done from a blocking context.
+
+use crate::settings::MailSettings;
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::{Message, SmtpTransport, Transport};
+use std::error::Error;
+
+/// Sends email through the configured SMTP server.
+pub struct Mailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl Mailer {
+    pub fn from_settings(settings: &MailSettings) -> Result<Self, lettre::transport::smtp::Error> {
+        Ok(Mailer {
+            transport: SmtpTransport::from_url(&settings.smtp_url)?.build(),
+            from: settings.from.clone(),
+        })
+    }
+
+    /// Sends a plain text message to a single recipient.
+    pub fn send(
+        &self,
+        to: &str,
+        subject: &str,
+        body: String,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to.parse()?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)?;
+        self.transport.send(&message)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,306 @@
+//! # Metrics Module
+//!
+//! This module collects the Prometheus metrics served at `GET /metrics`: request counts and latencies per
+//! route, login outcomes, token validation outcomes, JWKS fetch latency, password hashing durations and the
+//! state of the database connection pool. Metrics are recorded in a process-wide registry, so code deep in
+//! the request path can record them without access to the application data.
+
+use crate::errors::ServiceError;
+use crate::Pool;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+// Buckets for short waits, such as checking a connection out of the pool, in seconds.
+const WAIT_BUCKETS: &[f64] = &[
+    0.0001, 0.0005, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 30.0,
+];
+
+/// The application's Prometheus metrics.
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,        // By method, route and status.
+    http_request_duration: HistogramVec, // By method and route.
+    logins: IntCounterVec,               // By outcome and reason.
+    token_validations: IntCounterVec,    // By outcome.
+    jwks_fetch_duration: HistogramVec,   // By outcome.
+    password_hashing_duration: HistogramVec, // By operation and algorithm.
+    pool_connections: IntGaugeVec,       // By state: idle or in use.
+    pool_wait_duration: Histogram,       // Time spent waiting for a connection.
+    pool_timeouts: IntCounter,           // Checkouts that gave up waiting.
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new();
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled"),
+            &["method", "route", "status"],
+        )?;
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time taken to handle HTTP requests",
+            ),
+            &["method", "route"],
+        )?;
+        let logins = IntCounterVec::new(
+            Opts::new("auth_logins_total", "Login attempts"),
+            &["outcome", "reason"],
+        )?;
+        let token_validations = IntCounterVec::new(
+            Opts::new(
+                "auth_token_validations_total",
+                "Bearer tokens validated on protected routes",
+            ),
+            &["outcome"],
+        )?;
+        let jwks_fetch_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "auth_jwks_fetch_duration_seconds",
+                "Time taken to fetch the JWKS from the Auth0 authority",
+            ),
+            &["outcome"],
+        )?;
+        let password_hashing_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "password_hashing_duration_seconds",
+                "Time taken to hash or verify a password, excluding the wait for a hashing slot",
+            ),
+            &["operation", "algorithm"],
+        )?;
+        let pool_connections = IntGaugeVec::new(
+            Opts::new(
+                "db_pool_connections",
+                "Database connections in the pool, by state",
+            ),
+            &["state"],
+        )?;
+        let pool_wait_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "db_pool_wait_duration_seconds",
+                "Time spent waiting to check a connection out of the pool",
+            )
+            .buckets(WAIT_BUCKETS.to_vec()),
+        )?;
+        let pool_timeouts = IntCounter::new(
+            "db_pool_timeouts_total",
+            "Connection checkouts that timed out waiting for the pool",
+        )?;
+
+        registry.register(Box::new(http_requests.clone()))?;
+        registry.register(Box::new(http_request_duration.clone()))?;
+        registry.register(Box::new(logins.clone()))?;
+        registry.register(Box::new(token_validations.clone()))?;
+        registry.register(Box::new(jwks_fetch_duration.clone()))?;
+        registry.register(Box::new(password_hashing_duration.clone()))?;
+        registry.register(Box::new(pool_connections.clone()))?;
+        registry.register(Box::new(pool_wait_duration.clone()))?;
+        registry.register(Box::new(pool_timeouts.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            http_requests,
+            http_request_duration,
+            logins,
+            token_validations,
+            jwks_fetch_duration,
+            password_hashing_duration,
+            pool_connections,
+            pool_wait_duration,
+            pool_timeouts,
+        })
+    }
+}
+
+/// Returns the process-wide metrics, registering them on first use.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("metric definitions must be valid"))
+}
+
+/// Records the outcome of a login attempt, labelled with the reason it failed.
+pub fn record_login<T>(result: &Result<T, ServiceError>, known_account: bool) {
+    let (outcome, reason) = match result {
+        Ok(_) => ("success", "none"),
+        Err(e) => ("failure", login_failure_reason(e, known_account)),
+    };
+    metrics().logins.with_label_values(&[outcome, reason]).inc();
+}
+
+/// Classifies a failed login by the error returned to the client. Unknown emails are answered like wrong
+/// passwords, so `known_account` tells them apart.
+pub fn login_failure_reason(e: &ServiceError, known_account: bool) -> &'static str {
+    match e {
+        ServiceError::Validation(_) | ServiceError::BadRequest(_) => "invalid_request",
+        ServiceError::Unauthorized if !known_account => "unknown_user",
+        ServiceError::Unauthorized => "invalid_credentials",
+        ServiceError::AccountDisabled => "account_disabled",
+        ServiceError::AccountSuspended(_) => "account_suspended",
+        ServiceError::PasswordResetRequired => "password_reset_required",
+        ServiceError::JWKSFetchError => "token_issuer_unavailable",
+        _ => "internal_error",
+    }
+}
+
+/// Records the outcome of validating a bearer token, such as `valid` or `session_rejected`.
+pub fn record_token_validation(outcome: &str) {
+    metrics()
+        .token_validations
+        .with_label_values(&[outcome])
+        .inc();
+}
+
+/// Records how long fetching the JWKS took and whether it succeeded.
+pub fn record_jwks_fetch(duration: Duration, succeeded: bool) {
+    let outcome = if succeeded { "success" } else { "failure" };
+    metrics()
+        .jwks_fetch_duration
+        .with_label_values(&[outcome])
+        .observe(duration.as_secs_f64());
+}
+
+/// Records how long hashing (`hash`) or verifying (`verify`) a password took.
+pub fn record_password_hashing(operation: &str, algorithm: &str, duration: Duration) {
+    metrics()
+        .password_hashing_duration
+        .with_label_values(&[operation, algorithm])
+        .observe(duration.as_secs_f64());
+}
+
+/// Records the wait of connection checkouts from the database pool.
+#[derive(Debug)]
+pub struct PoolEvents;
+
+impl r2d2::HandleEvent for PoolEvents {
+    fn handle_checkout(&self, event: r2d2::event::CheckoutEvent) {
+        metrics()
+            .pool_wait_duration
+            .observe(event.duration().as_secs_f64());
+    }
+
+    fn handle_timeout(&self, event: r2d2::event::TimeoutEvent) {
+        metrics().pool_timeouts.inc();
+        metrics()
+            .pool_wait_duration
+            .observe(event.timeout().as_secs_f64());
+    }
+}
+
+/// Middleware counting requests and timing them per route.
+///
+/// Routes are labelled with their pattern, such as `/admin/users/{id}`, so the number of series stays
+/// bounded; requests matching no route share the `unmatched` label.
+pub async fn track_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let res = next.call(req).await;
+    let status = match &res {
+        Ok(res) => res.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+
+    let metrics = metrics();
+    metrics
+        .http_requests
+        .with_label_values(&[method.as_str(), route.as_str(), status.as_str()])
+        .inc();
+    metrics
+        .http_request_duration
+        .with_label_values(&[method.as_str(), route.as_str()])
+        .observe(started.elapsed().as_secs_f64());
+    res
+}
+
+/// Handler for the Prometheus scrape endpoint.
+///
+/// # Arguments
+///
+/// * `db`: Database connection pool, whose current state is reported.
+///
+/// # Returns
+///
+/// The metrics in the Prometheus text exposition format.
+pub async fn export(db: web::Data<Pool>) -> HttpResponse {
+    let metrics = metrics();
+    let state = db.state();
+    let idle = i64::from(state.idle_connections);
+    metrics
+        .pool_connections
+        .with_label_values(&["idle"])
+        .set(idle);
+    metrics
+        .pool_connections
+        .with_label_values(&["in_use"])
+        .set(i64::from(state.connections) - idle);
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(e) = encoder.encode(&metrics.registry.gather(), &mut body) {
+        error!("Encoding metrics failed: {:?}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_login_failures() {
+        assert_eq!(
+            login_failure_reason(&ServiceError::Unauthorized, true),
+            "invalid_credentials"
+        );
+        assert_eq!(
+            login_failure_reason(&ServiceError::Unauthorized, false),
+            "unknown_user"
+        );
+        assert_eq!(
+            login_failure_reason(
+                &ServiceError::AccountSuspended(chrono::Local::now().naive_local()),
+                true
+            ),
+            "account_suspended"
+        );
+        assert_eq!(
+            login_failure_reason(&ServiceError::InternalServerError, true),
+            "internal_error"
+        );
+    }
+
+    #[test]
+    fn exports_recorded_metrics() {
+        record_login::<()>(&Err(ServiceError::Unauthorized), false);
+        record_token_validation("valid");
+
+        let text = {
+            let mut body = Vec::new();
+            TextEncoder::new()
+                .encode(&metrics().registry.gather(), &mut body)
+                .unwrap();
+            String::from_utf8(body).unwrap()
+        };
+        assert!(text.contains(r#"auth_logins_total{outcome="failure",reason="unknown_user"}"#));
+        assert!(text.contains(r#"auth_token_validations_total{outcome="valid"}"#));
+    }
+}
@@ -5,21 +5,60 @@
 //! - `User`: Struct for querying existing users from the database.
 //! - `NewUser`: Struct for inserting new users into the database.
 //! - `LoginCredentials`: Struct for handling login requests.
+//! - `PasswordChange`: Struct for handling password change requests.
+//! - `PasswordReset`: Struct for handling password changes after a forced reset.
+//! - `Session` and `NewSession`: Structs for checking and recording tokens and session cookies issued at login.
+//! - `AuditEvent` and `NewAuditEvent`: Structs for reading and appending security events.
+//! - `Login` and `NewLogin`: Structs for the login history.
 
 // Import necessary crates and modules for ORM and serialization.
+use crate::errors::ServiceError;
 use crate::schema::*;
 use serde::{Deserialize, Serialize};
+use validator::Validate;
 
 // User struct for querying existing users from the database.
-// It implements Serialize and Deserialize for easy conversion between JSON and Rust structs.
+// It implements Serialize and Deserialize for easy conversion between JSON and Rust structs. Responses
+// return a view of it instead, such as `handlers::SignedUpUser` or `admin::AdminUser`, and the secret
+// hashes are never serialized.
 #[derive(Serialize, Debug, Queryable, Deserialize)]
 pub struct User {
-    pub id: i32,                           // Unique identifier for the user.
-    pub first_name: String,                // User's first name.
-    pub last_name: String,                 // User's last name.
-    pub email: String,                     // User's email address.
-    pub user_password: String,             // Hashed password for the user.
+    pub id: i32,            // Unique identifier for the user.
+    pub first_name: String, // User's first name.
+    pub last_name: String,  // User's last name.
+    pub email: String,      // User's email address.
+    #[serde(skip_serializing)]
+    pub user_password: String, // Hashed password for the user.
     pub created_at: chrono::NaiveDateTime, // Timestamp of user creation.
+    pub disabled_at: Option<chrono::NaiveDateTime>, // Set while an admin has disabled the account.
+    pub password_reset_required: bool, // The user must change their password before logging in.
+    pub disabled_reason: Option<String>, // Why the account was disabled or suspended, for support staff.
+    pub suspended_until: Option<chrono::NaiveDateTime>, // End of a temporary suspension.
+    pub deleted_at: Option<chrono::NaiveDateTime>, // Set when the user deleted their account.
+    pub anonymized_at: Option<chrono::NaiveDateTime>, // Set once personal data has been erased.
+    pub email_normalized: Option<String>, // Case-insensitive identity key of the email address.
+    #[serde(skip_serializing)]
+    pub password_reset_token_hash: Option<String>, // Hash of the token emailed for a forced reset.
+    pub password_reset_expires_at: Option<chrono::NaiveDateTime>, // Expiry of that token.
+}
+
+impl User {
+    // Returns an error while the account is deleted, disabled or suspended.
+    // Locked accounts can neither log in nor use tokens issued to them earlier.
+    pub fn check_not_locked(&self) -> Result<(), ServiceError> {
+        if self.deleted_at.is_some() {
+            return Err(ServiceError::Unauthorized);
+        }
+        if self.disabled_at.is_some() {
+            return Err(ServiceError::AccountDisabled);
+        }
+        match self.suspended_until {
+            Some(until) if until > chrono::Local::now().naive_local() => {
+                Err(ServiceError::AccountSuspended(until))
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 // NewUser struct for inserting new users into the database.
@@ -29,15 +68,146 @@ pub struct User {
 pub struct NewUser {
     pub first_name: String,                // User's first name.
     pub last_name: String,                 // User's last name.
-    pub email: String,                     // User's email address.
+    pub email: String,                     // User's email address, normalized by the email module.
+    pub email_normalized: String,          // Case-insensitive identity key of the email address.
     pub user_password: String,             // Hashed password for the user.
     pub created_at: chrono::NaiveDateTime, // Timestamp of user creation, set at the time of insertion.
 }
 
 // LoginCredentials struct for handling login requests.
 // It includes fields for email and password as provided by the user during login attempts.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
 pub struct LoginCredentials {
-    pub email: String,    // Email provided by the user for login.
+    #[validate(length(min = 1, max = 254))]
+    pub email: String, // Email provided by the user for login.
+    #[validate(length(min = 1, max = 1024))]
     pub password: String, // Password provided by the user for login.
 }
+
+// PasswordChange struct for handling password change requests of the signed-in user.
+// The current password must be supplied alongside the new one.
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct PasswordChange {
+    #[validate(length(min = 1, max = 1024))]
+    pub current_password: String, // Current password, verified before the change.
+    #[validate(length(min = 8, max = 1024))]
+    pub new_password: String, // New password, checked against the password policy.
+}
+
+// PasswordReset struct for handling password changes of users flagged for a forced reset,
+// who cannot log in and identify themselves with their email and the token emailed to them instead.
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct PasswordReset {
+    #[validate(length(min = 1, max = 254))]
+    pub email: String, // Email of the account whose password is changed.
+    #[validate(length(min = 1, max = 256))]
+    pub token: String, // One-time token from the password reset email.
+    #[validate(length(min = 8, max = 1024))]
+    pub new_password: String, // New password, checked against the password policy.
+}
+
+// Session struct for checking the token or session cookie a request presents.
+#[derive(Debug, Queryable, Selectable)]
+#[diesel(table_name = sessions)]
+pub struct Session {
+    pub id: i32,
+    pub created_at: chrono::NaiveDateTime,
+    pub expires_at: chrono::NaiveDateTime,
+    pub revoked_at: Option<chrono::NaiveDateTime>,
+    pub last_used_at: Option<chrono::NaiveDateTime>, // Accurate to a few minutes.
+}
+
+impl Session {
+    // Returns true while the session is neither revoked nor expired.
+    pub fn is_active(&self) -> bool {
+        self.revoked_at.is_none() && self.expires_at > chrono::Local::now().naive_local()
+    }
+}
+
+// NewSession struct for recording a token or session cookie issued at login.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = sessions)]
+pub struct NewSession {
+    pub user_id: i32,                      // User the token was issued to.
+    pub token_hash: String, // Hex-encoded SHA-256 hash of the access token or session id.
+    pub created_at: chrono::NaiveDateTime, // Timestamp of the login.
+    pub expires_at: chrono::NaiveDateTime, // Expiry of the access token or session.
+    pub login_id: Option<i32>, // Login the token was issued at, with its device.
+    pub kind: &'static str, // `bearer` or `cookie`, see `sessions::SessionKind`.
+}
+
+// AuditEvent struct for reading security events from the audit log.
+#[derive(Serialize, Debug, Queryable)]
+pub struct AuditEvent {
+    pub id: i64,                            // Position of the event in the hash chain.
+    pub occurred_at: chrono::NaiveDateTime, // Time of the event, to the microsecond.
+    pub event_type: String,                 // Kind of event, such as `login` or `user_disabled`.
+    pub outcome: String,                    // `success` or `failure`.
+    pub actor: Option<String>,              // Who acted: `user:<id>`, `admin:<subject>` or unknown.
+    pub target_user_id: Option<i32>,        // User the event concerns.
+    pub ip_address: Option<String>, // Address of the client that made the request, until erased.
+    pub user_agent: Option<String>, // User agent of the client that made the request, until erased.
+    pub request_id: Option<String>, // Id of the request, as in the logs.
+    pub details: String,            // JSON object with event-specific details.
+    pub prev_hash: String,          // Hash of the previous event in the chain.
+    pub hash: String,               // Hash of this event's contents and `prev_hash`.
+}
+
+// NewAuditEvent struct for appending a security event to the audit log.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = audit_events)]
+pub struct NewAuditEvent {
+    pub occurred_at: chrono::NaiveDateTime, // Time of the event, truncated to microseconds.
+    pub event_type: String,                 // Kind of event.
+    pub outcome: String,                    // `success` or `failure`.
+    pub actor: Option<String>,              // Who acted.
+    pub target_user_id: Option<i32>,        // User the event concerns.
+    pub request_id: Option<String>,         // Id of the request.
+    pub details: String,                    // JSON object with event-specific details.
+    pub prev_hash: String,                  // Hash of the previous event in the chain.
+    pub hash: String,                       // Hash of this event's contents and `prev_hash`.
+}
+
+// NewAuditEventClient struct for recording the client an audit event came from, outside the hash chain
+// so that it can be erased with the account.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = audit_event_clients)]
+pub struct NewAuditEventClient {
+    pub event_id: i64,              // Event the client caused.
+    pub ip_address: Option<String>, // Address of the client.
+    pub user_agent: Option<String>, // User agent of the client.
+}
+
+// Login struct for reading a user's login history.
+#[derive(Serialize, Debug, Queryable, Selectable)]
+#[diesel(table_name = logins)]
+pub struct Login {
+    pub id: i32,                             // Unique identifier for the login.
+    pub logged_in_at: chrono::NaiveDateTime, // Timestamp of the login.
+    pub ip_address: Option<String>,          // Address of the client.
+    pub user_agent: Option<String>,          // User agent of the client.
+    pub browser: String,                     // Browser parsed from the user agent.
+    pub operating_system: String,            // Operating system parsed from the user agent.
+    pub device_type: String, // `pc`, `smartphone`, `mobilephone`, `appliance`, `crawler` or `UNKNOWN`.
+    pub country: Option<String>, // ISO code of the country the address is located in.
+    pub city: Option<String>, // City the address is located in.
+}
+
+// NewLogin struct for recording a successful login.
+#[derive(Insertable, Debug)]
+#[diesel(table_name = logins)]
+pub struct NewLogin {
+    pub user_id: i32,                        // User who logged in.
+    pub logged_in_at: chrono::NaiveDateTime, // Timestamp of the login.
+    pub ip_address: Option<String>,          // Address of the client.
+    pub user_agent: Option<String>,          // User agent of the client.
+    pub browser: String,                     // Browser parsed from the user agent.
+    pub operating_system: String,            // Operating system parsed from the user agent.
+    pub device_type: String,                 // Kind of device parsed from the user agent.
+    pub country: Option<String>,             // ISO code of the country the address is located in.
+    pub city: Option<String>,                // City the address is located in.
+    pub device_fingerprint: String,          // Hash identifying the device.
+}
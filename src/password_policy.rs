@@ -0,0 +1,57 @@
+//! # Password Policy Module
+//!
+//! This module decides whether a new password is acceptable. It is applied whenever a password is set,
+//! during sign-up and password change. When `BREACHED_PASSWORDS_PATH` is configured, passwords found in
+//! the local breached password corpus (see the breach module) are rejected; the check runs fully offline.
+
+use crate::breach::BreachedPasswords;
+use crate::errors::ServiceError;
+use crate::settings::Settings;
+use tracing::{error, info, warn};
+
+/// Rules applied to every newly chosen password.
+pub struct PasswordPolicy {
+    breached_passwords: Option<BreachedPasswords>,
+}
+
+impl PasswordPolicy {
+    /// Builds the policy from the settings.
+    ///
+    /// `BREACHED_PASSWORDS_PATH` may point at a Bloom filter file or a directory of range files.
+    /// When it is unset the breached password check is disabled.
+    pub fn from_settings(settings: &Settings) -> std::io::Result<Self> {
+        let breached_passwords = match &settings.breached_passwords_path {
+            Some(path) => Some(BreachedPasswords::open(path)?),
+            None => {
+                info!("BREACHED_PASSWORDS_PATH not set, breached password check disabled");
+                None
+            }
+        };
+
+        Ok(PasswordPolicy { breached_passwords })
+    }
+
+    /// Checks a candidate password against the policy.
+    ///
+    /// This may read range files from disk, so it should be called from a blocking context.
+    pub fn check(&self, password: &str) -> Result<(), ServiceError> {
+        if let Some(breached_passwords) = &self.breached_passwords {
+            match breached_passwords.contains(password) {
+                Ok(true) => {
+                    warn!("Rejected a password found in the breached password corpus");
+                    return Err(ServiceError::BadRequest(
+                        "This password has appeared in a data breach. Please choose a different password"
+                            .to_string(),
+                    ));
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    error!("Breached password lookup failed: {:?}", e);
+                    return Err(ServiceError::InternalServerError);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
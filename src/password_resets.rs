@@ -0,0 +1,146 @@
+//! # Password Resets Module
+//!
+//! This module issues the one-time tokens that let a user complete a password reset forced by an admin.
+//! Forcing a reset revokes the user's sessions, since the account may be compromised, so the user can prove
+//! who they are neither with a session nor with their current password. Instead a random token is emailed
+//! to them; only its SHA-256 hash is stored, it expires after `TOKEN_LIFETIME_SECS`, and it is cleared when
+//! the new password is stored, so it works once.
+
+use crate::mailer::Mailer;
+use crate::models::User;
+use crate::sessions;
+use crate::settings::Settings;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand_core::{OsRng, RngCore};
+use std::error::Error;
+use subtle::ConstantTimeEq;
+use tracing::info;
+
+/// How long a password reset token is valid after the admin forced the reset.
+pub const TOKEN_LIFETIME_SECS: i64 = 86_400;
+
+// Number of random bytes in a reset token.
+const TOKEN_BYTES: usize = 32;
+
+/// Sends password reset tokens by email.
+pub struct PasswordResets {
+    mailer: Option<Mailer>,
+}
+
+impl PasswordResets {
+    pub fn from_settings(settings: &Settings) -> std::io::Result<Self> {
+        let mailer = match &settings.mail {
+            Some(mail) => Some(Mailer::from_settings(mail).map_err(std::io::Error::other)?),
+            None => {
+                info!("SMTP_URL not set, password resets cannot be forced");
+                None
+            }
+        };
+        Ok(PasswordResets { mailer })
+    }
+
+    /// Returns true if reset tokens can be sent, which requires an SMTP server.
+    pub fn is_enabled(&self) -> bool {
+        self.mailer.is_some()
+    }
+
+    /// Emails a reset token to the user. Blocks until the SMTP server has accepted the message.
+    pub fn send(&self, to: &str, token: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mailer = self
+            .mailer
+            .as_ref()
+            .ok_or("password reset emails are not configured")?;
+        mailer.send(to, "Reset your password", reset_message(token))
+    }
+}
+
+/// Generates a reset token of 256 random bits, base64url-encoded, returning it along with the hash under
+/// which it is stored.
+pub fn generate_token() -> (String, String) {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+    let token_hash = sessions::token_hash(&token);
+    (token, token_hash)
+}
+
+/// Expiry of a reset token issued now.
+pub fn token_expiry() -> chrono::NaiveDateTime {
+    chrono::Local::now().naive_local() + chrono::Duration::seconds(TOKEN_LIFETIME_SECS)
+}
+
+/// Returns true if the token is the user's current, unexpired reset token.
+pub fn token_matches(user: &User, token: &str) -> bool {
+    let (Some(stored_hash), Some(expires_at)) = (
+        &user.password_reset_token_hash,
+        user.password_reset_expires_at,
+    ) else {
+        return false;
+    };
+    let matches: bool = sessions::token_hash(token)
+        .as_bytes()
+        .ct_eq(stored_hash.as_bytes())
+        .into();
+    matches && expires_at > chrono::Local::now().naive_local()
+}
+
+// Text of the password reset email.
+fn reset_message(token: &str) -> String {
+    format!(
+        "An administrator has required you to choose a new password. You have been signed out everywhere \
+         and cannot sign in until you do.\n\n\
+         To choose a new password, send your email address, the token below and your new password to \
+         POST /users/password/reset within {} hours:\n\n{}\n\n\
+         If you did not expect this email, contact support.\n",
+        TOKEN_LIFETIME_SECS / 3600,
+        token
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flagged_user(token_hash: Option<String>, expires_at: chrono::NaiveDateTime) -> User {
+        let now = chrono::Local::now().naive_local();
+        User {
+            id: 7,
+            first_name: "Ada".to_string(),
+            last_name: "Lovelace".to_string(),
+            email: "ada@example.com".to_string(),
+            user_password: String::new(),
+            created_at: now,
+            disabled_at: None,
+            password_reset_required: true,
+            disabled_reason: None,
+            suspended_until: None,
+            deleted_at: None,
+            anonymized_at: None,
+            email_normalized: Some("ada@example.com".to_string()),
+            password_reset_token_hash: token_hash,
+            password_reset_expires_at: Some(expires_at),
+        }
+    }
+
+    #[test]
+    fn accepts_only_the_current_unexpired_token() {
+        let (token, token_hash) = generate_token();
+        let (other_token, _) = generate_token();
+        assert_ne!(token, other_token);
+
+        let user = flagged_user(Some(token_hash.clone()), token_expiry());
+        assert!(token_matches(&user, &token));
+        assert!(!token_matches(&user, &other_token));
+        assert!(!token_matches(&user, &token_hash));
+
+        let expired = flagged_user(
+            Some(token_hash),
+            chrono::Local::now().naive_local() - chrono::Duration::seconds(1),
+        );
+        assert!(!token_matches(&expired, &token));
+
+        let without_token = flagged_user(None, token_expiry());
+        assert!(!token_matches(&without_token, &token));
+    }
+}
@@ -0,0 +1,190 @@
+//! # Request ID Module
+//!
+//! This module gives every request an id that support can use to find the server logs of a failed request.
+//! A caller may send its own id in the `X-Request-Id` header, for instance one assigned by a proxy; otherwise
+//! a random UUID is generated. The id is recorded on the request span, so it appears on every log line
+//! written during the request, is echoed in the `X-Request-Id` response header and is included in the body
+//! of every error response.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage};
+use std::fmt;
+
+/// Header carrying the request id, on requests and responses.
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest request id accepted from a caller.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Id of the request being handled, stored in the request extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// Uses the id sent by the caller if it is acceptable, or generates one.
+    fn for_request(header: Option<&HeaderValue>) -> Self {
+        header
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| is_acceptable(id))
+            .map(|id| RequestId(id.to_string()))
+            .unwrap_or_else(|| RequestId(uuid::Uuid::new_v4().to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// Ids from callers end up in logs and response headers, so only short ids of plain characters are kept.
+fn is_acceptable(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+tokio::task_local! {
+    // Id of the request handled by the current task, read when building error responses.
+    static CURRENT: RequestId;
+}
+
+/// Returns the id of the request being handled, if any.
+pub fn current() -> Option<RequestId> {
+    CURRENT.try_with(RequestId::clone).ok()
+}
+
+/// Middleware assigning the request id and echoing it in the response.
+///
+/// Error responses are built here, while the id is still known, so that error bodies include it.
+pub async fn assign(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let request_id = RequestId::for_request(req.headers().get(&REQUEST_ID_HEADER));
+    req.extensions_mut().insert(request_id.clone());
+    let header = HeaderValue::from_str(request_id.as_str()).ok();
+
+    match CURRENT.scope(request_id.clone(), next.call(req)).await {
+        Ok(mut res) => {
+            if let Some(header) = header {
+                res.headers_mut().insert(REQUEST_ID_HEADER, header);
+            }
+            Ok(res)
+        }
+        Err(e) => {
+            let mut response = CURRENT.sync_scope(request_id, || e.error_response());
+            if let Some(header) = header {
+                response.headers_mut().insert(REQUEST_ID_HEADER, header);
+            }
+            Err(InternalError::from_response(e, response).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ServiceError;
+    use actix_web::body::to_bytes;
+    use actix_web::test::{
+        call_service, init_service, read_body_json, try_call_service, TestRequest,
+    };
+    use actix_web::{middleware, web, App, HttpResponse};
+
+    async fn missing() -> Result<HttpResponse, ServiceError> {
+        Err(ServiceError::NotFound)
+    }
+
+    async fn forbid(
+        _req: ServiceRequest,
+        _next: Next<impl MessageBody>,
+    ) -> Result<ServiceResponse, Error> {
+        Err(ServiceError::Forbidden.into())
+    }
+
+    #[actix_rt::test]
+    async fn echoes_the_request_id_in_headers_and_error_bodies() {
+        let app = init_service(
+            App::new()
+                .wrap(middleware::from_fn(assign))
+                .route("/missing", web::get().to(missing)),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/missing")
+            .insert_header(("X-Request-Id", "support-ticket-42"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(
+            res.headers().get(&REQUEST_ID_HEADER).unwrap(),
+            "support-ticket-42"
+        );
+        let body: serde_json::Value = read_body_json(res).await;
+        assert_eq!(body["request_id"], "support-ticket-42");
+
+        let req = TestRequest::get()
+            .uri("/missing")
+            .insert_header(("X-Request-Id", "id with spaces"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        let generated = res
+            .headers()
+            .get(&REQUEST_ID_HEADER)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(uuid::Uuid::parse_str(&generated).is_ok(), "{}", generated);
+        let body: serde_json::Value = read_body_json(res).await;
+        assert_eq!(body["request_id"], generated.as_str());
+    }
+
+    #[actix_rt::test]
+    async fn includes_the_request_id_in_errors_from_middleware() {
+        let app = init_service(
+            App::new()
+                .wrap(middleware::from_fn(forbid))
+                .wrap(middleware::from_fn(assign))
+                .route("/missing", web::get().to(missing)),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/missing")
+            .insert_header(("X-Request-Id", "support-ticket-43"))
+            .to_request();
+        let res = try_call_service(&app, req)
+            .await
+            .err()
+            .unwrap()
+            .error_response();
+        assert_eq!(
+            res.headers().get(&REQUEST_ID_HEADER).unwrap(),
+            "support-ticket-43"
+        );
+        let body = to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["request_id"], "support-ticket-43");
+    }
+
+    #[test]
+    fn rejects_unsafe_request_ids() {
+        assert!(is_acceptable("3f2c9a1e-7b1d-4c55-9f0e-2d8f6a1b4c3d"));
+        assert!(!is_acceptable(""));
+        assert!(!is_acceptable("id with spaces"));
+        assert!(!is_acceptable(&"a".repeat(MAX_REQUEST_ID_LEN + 1)));
+        assert_eq!(current(), None);
+    }
+}
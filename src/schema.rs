@@ -1,5 +1,58 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    audit_event_clients (event_id) {
+        event_id -> Int8,
+        ip_address -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    audit_events (id) {
+        id -> Int8,
+        occurred_at -> Timestamp,
+        event_type -> Text,
+        outcome -> Text,
+        actor -> Nullable<Text>,
+        target_user_id -> Nullable<Int4>,
+        request_id -> Nullable<Text>,
+        details -> Text,
+        prev_hash -> Text,
+        hash -> Text,
+    }
+}
+
+diesel::table! {
+    logins (id) {
+        id -> Int4,
+        user_id -> Int4,
+        logged_in_at -> Timestamp,
+        ip_address -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        browser -> Text,
+        operating_system -> Text,
+        device_type -> Text,
+        country -> Nullable<Text>,
+        city -> Nullable<Text>,
+        device_fingerprint -> Text,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token_hash -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+        login_id -> Nullable<Int4>,
+        last_used_at -> Nullable<Timestamp>,
+        kind -> Text,
+    }
+}
+
 diesel::table! {
     users (id) {
         id -> Int4,
@@ -8,5 +61,27 @@ diesel::table! {
         email -> Text,
         user_password -> Text,
         created_at -> Timestamp,
+        disabled_at -> Nullable<Timestamp>,
+        password_reset_required -> Bool,
+        disabled_reason -> Nullable<Text>,
+        suspended_until -> Nullable<Timestamp>,
+        deleted_at -> Nullable<Timestamp>,
+        anonymized_at -> Nullable<Timestamp>,
+        email_normalized -> Nullable<Text>,
+        password_reset_token_hash -> Nullable<Text>,
+        password_reset_expires_at -> Nullable<Timestamp>,
     }
 }
+
+diesel::joinable!(audit_event_clients -> audit_events (event_id));
+diesel::joinable!(logins -> users (user_id));
+diesel::joinable!(sessions -> logins (login_id));
+diesel::joinable!(sessions -> users (user_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    audit_event_clients,
+    audit_events,
+    logins,
+    sessions,
+    users,
+);
@@ -0,0 +1,217 @@
+//! # Sessions Module
+//!
+//! This module records the access tokens issued at login, so that a token presented later can be traced
+//! back to the user it was issued to. Tokens come from Auth0's client credentials flow and carry no user
+//! identity of their own. Only a SHA-256 hash of each token is stored, and it is unique: a token already
+//! recorded for one session is never recorded for another.
+//!
+//! Each session is linked to the login that created it, whose IP address and device are shown when users
+//! list their active sessions, and can be revoked on its own so a lost device can be signed out remotely.
+//!
+//! Browser clients may use a session cookie instead of a bearer token (see `cookie_sessions`). Such
+//! sessions are stored the same way, with the hash of their random session id, and are told apart by their
+//! kind so that a token is never accepted as a session id or the other way around.
+
+use crate::models::{NewSession, Session, User};
+use crate::schema::{logins, sessions, users};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Lifetime assumed for tokens whose response does not state one (Auth0's default of 24 hours).
+const DEFAULT_TOKEN_LIFETIME_SECS: i64 = 86_400;
+
+/// How stale `last_used_at` may get before a request updates it, to avoid a write on every request.
+pub const LAST_USED_RESOLUTION_SECS: i64 = 300;
+
+/// How the client of a session authenticates its requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionKind {
+    /// The Auth0 access token returned by login, sent as a bearer token.
+    #[default]
+    Bearer,
+    /// A random session id kept in an HttpOnly cookie.
+    Cookie,
+}
+
+impl SessionKind {
+    /// Returns the value stored in `sessions.kind`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SessionKind::Bearer => "bearer",
+            SessionKind::Cookie => "cookie",
+        }
+    }
+}
+
+/// An active session as shown to its user, with the device it was issued to.
+#[derive(Debug, Serialize, Queryable)]
+pub struct ActiveSession {
+    pub id: i32,
+    pub kind: String, // `bearer` or `cookie`.
+    pub created_at: chrono::NaiveDateTime,
+    pub expires_at: chrono::NaiveDateTime,
+    pub last_used_at: Option<chrono::NaiveDateTime>, // Accurate to a few minutes.
+    pub ip_address: Option<String>,
+    pub browser: Option<String>,
+    pub operating_system: Option<String>,
+    pub device_type: Option<String>,
+    pub country: Option<String>,
+    pub city: Option<String>,
+}
+
+/// Returns the hex-encoded SHA-256 hash under which a token is stored.
+pub fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Returns the lifetime of a token, given the `expires_in` of the response it was issued in.
+pub fn token_lifetime(expires_in: Option<i64>) -> chrono::Duration {
+    chrono::Duration::seconds(expires_in.unwrap_or(DEFAULT_TOKEN_LIFETIME_SECS))
+}
+
+/// Records a token or session id issued to a user at login, pruning the user's expired sessions.
+pub fn record_session(
+    conn: &mut PgConnection,
+    user_id: i32,
+    kind: SessionKind,
+    token: &str,
+    lifetime: chrono::Duration,
+    login_id: Option<i32>,
+) -> QueryResult<()> {
+    let now = chrono::Local::now().naive_local();
+
+    conn.transaction(|conn| {
+        diesel::delete(
+            sessions::table
+                .filter(sessions::user_id.eq(user_id))
+                .filter(sessions::expires_at.le(now)),
+        )
+        .execute(conn)?;
+
+        diesel::insert_into(sessions::table)
+            .values(NewSession {
+                user_id,
+                token_hash: token_hash(token),
+                created_at: now,
+                expires_at: now + lifetime,
+                login_id,
+                kind: kind.as_str(),
+            })
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// Finds the session of a token or session id issued at login, along with the user it was issued to.
+///
+/// Returns `None` for tokens not issued through login, such as machine-to-machine tokens.
+pub fn find_session(
+    conn: &mut PgConnection,
+    kind: SessionKind,
+    token: &str,
+) -> QueryResult<Option<(Session, User)>> {
+    sessions::table
+        .inner_join(users::table)
+        .filter(sessions::token_hash.eq(token_hash(token)))
+        .filter(sessions::kind.eq(kind.as_str()))
+        .select((Session::as_select(), users::all_columns))
+        .first(conn)
+        .optional()
+}
+
+/// Notes that a session was just used, unless that was already noted in the last few minutes.
+pub fn touch_session(conn: &mut PgConnection, session_id: i32) -> QueryResult<()> {
+    let now = chrono::Local::now().naive_local();
+    let stale = now - chrono::Duration::seconds(LAST_USED_RESOLUTION_SECS);
+    diesel::update(
+        sessions::table.find(session_id).filter(
+            sessions::last_used_at
+                .is_null()
+                .or(sessions::last_used_at.lt(stale)),
+        ),
+    )
+    .set(sessions::last_used_at.eq(now))
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Notes that a session was just used and moves its expiry, for sessions that expire when left unused.
+pub fn extend_session(
+    conn: &mut PgConnection,
+    session_id: i32,
+    expires_at: chrono::NaiveDateTime,
+) -> QueryResult<()> {
+    diesel::update(sessions::table.find(session_id))
+        .set((
+            sessions::last_used_at.eq(chrono::Local::now().naive_local()),
+            sessions::expires_at.eq(expires_at),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Lists a user's sessions that are neither revoked nor expired, most recent first.
+pub fn active_sessions(conn: &mut PgConnection, user_id: i32) -> QueryResult<Vec<ActiveSession>> {
+    sessions::table
+        .left_join(logins::table)
+        .filter(sessions::user_id.eq(user_id))
+        .filter(sessions::revoked_at.is_null())
+        .filter(sessions::expires_at.gt(chrono::Local::now().naive_local()))
+        .order((sessions::created_at.desc(), sessions::id.desc()))
+        .select((
+            sessions::id,
+            sessions::kind,
+            sessions::created_at,
+            sessions::expires_at,
+            sessions::last_used_at,
+            logins::ip_address.nullable(),
+            logins::browser.nullable(),
+            logins::operating_system.nullable(),
+            logins::device_type.nullable(),
+            logins::country.nullable(),
+            logins::city.nullable(),
+        ))
+        .load(conn)
+}
+
+/// Revokes one of a user's active sessions, returning `false` if there is no such session.
+pub fn revoke_session(conn: &mut PgConnection, user_id: i32, session_id: i32) -> QueryResult<bool> {
+    let revoked = diesel::update(
+        sessions::table
+            .find(session_id)
+            .filter(sessions::user_id.eq(user_id))
+            .filter(sessions::revoked_at.is_null()),
+    )
+    .set(sessions::revoked_at.eq(chrono::Local::now().naive_local()))
+    .execute(conn)?;
+    Ok(revoked > 0)
+}
+
+/// Revokes all of a user's active sessions except one, returning how many were revoked.
+pub fn revoke_other_sessions(
+    conn: &mut PgConnection,
+    user_id: i32,
+    keep_session_id: i32,
+) -> QueryResult<usize> {
+    diesel::update(
+        sessions::table
+            .filter(sessions::user_id.eq(user_id))
+            .filter(sessions::id.ne(keep_session_id))
+            .filter(sessions::revoked_at.is_null()),
+    )
+    .set(sessions::revoked_at.eq(chrono::Local::now().naive_local()))
+    .execute(conn)
+}
+
+/// Revokes all of a user's sessions that are still active, returning how many were revoked.
+pub fn revoke_user_sessions(conn: &mut PgConnection, user_id: i32) -> QueryResult<usize> {
+    diesel::update(
+        sessions::table
+            .filter(sessions::user_id.eq(user_id))
+            .filter(sessions::revoked_at.is_null()),
+    )
+    .set(sessions::revoked_at.eq(chrono::Local::now().naive_local()))
+    .execute(conn)
+}
@@ -0,0 +1,380 @@
+//! # Telemetry Module
+//!
+//! This module sets up the application's `tracing` subscriber. Log events are written to the console,
+//! filtered by `RUST_LOG`, and spans are exported to an OpenTelemetry collector over OTLP/HTTP when the
+//! `otlp_endpoint` setting is configured. Traces are continued from the W3C `traceparent` header of incoming
+//! requests and propagated in the same header on outbound requests to Auth0, so a login can be followed
+//! across services.
+//!
+//! Spans cover each request, the database calls made by the handlers, password hashing and the requests
+//! sent to Auth0. Logs are written as text or, with `LOG_FORMAT=json`, as one JSON object per line carrying
+//! the fields of the request span: the request id, route, user id and outcome. Either way they pass through
+//! the redaction policy first.
+
+use crate::client_ip;
+use crate::redaction::{RedactingWriter, RedactionPolicy};
+use crate::request_id::RequestId;
+use crate::settings::Settings;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::BlockingError;
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage};
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::trace::{TraceContextExt, TraceId, TracerProvider as _};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use std::time::Instant;
+use tracing::field::Empty;
+use tracing::{error, info, info_span, Instrument, Span};
+use tracing_actix_web::{DefaultRootSpanBuilder, RootSpanBuilder};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Format of the log lines written to the console, configured with `LOG_FORMAT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines (the default).
+    Text,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+/// Keeps the span exporter running; pending spans are flushed when it is dropped.
+pub struct Telemetry {
+    _provider: Option<SdkTracerProvider>,
+}
+
+/// Installs the global `tracing` subscriber, which also receives the events of libraries using `log`.
+///
+/// Events are logged at `info` in production and at `debug` otherwise unless `RUST_LOG` says differently.
+/// Spans are exported to `otlp_endpoint`, the base URL of an OTLP/HTTP collector, if one is given.
+/// Without valid settings, text logs are written with the default redaction policy.
+pub fn init(settings: Option<&Settings>) -> Telemetry {
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let run_mode = settings.map_or("development", |settings| settings.run_mode.as_str());
+    let otlp_endpoint = settings.and_then(|settings| settings.otlp_endpoint.as_deref());
+    let format = settings.map_or(LogFormat::Text, |settings| settings.log_format);
+    let redaction =
+        settings.map_or_else(RedactionPolicy::default, |settings| settings.log_redaction);
+
+    let writer = RedactingWriter::new(redaction, std::io::stdout);
+    let output = match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().with_writer(writer).boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .with_writer(writer)
+            .boxed(),
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::new(match run_mode {
+            "production" => "info",
+            _ => "debug", // Default to debug for development or any other unspecified mode
+        })
+    });
+    let provider = otlp_endpoint.map(tracer_provider).transpose();
+    let (provider, exporter_error) = match provider {
+        Ok(provider) => (provider, None),
+        Err(e) => (None, Some(e)),
+    };
+    let otel = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+    });
+
+    tracing_subscriber::registry()
+        .with(output)
+        .with(filter)
+        .with(otel)
+        .init();
+
+    if let Some(e) = exporter_error {
+        error!("Traces will not be exported: {}", e);
+    }
+    Telemetry {
+        _provider: provider,
+    }
+}
+
+/// Builds a tracer provider exporting spans in batches to the OTLP/HTTP collector at `endpoint`.
+pub fn tracer_provider(endpoint: &str) -> Result<SdkTracerProvider, String> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()
+        .map_err(|e| format!("invalid OTLP exporter configuration: {}", e))?;
+
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(env!("CARGO_PKG_NAME"))
+                .build(),
+        )
+        .build())
+}
+
+/// Root span of each request, with the request id assigned by the `request_id` middleware, the route,
+/// the id of the signed-in user and the outcome of the request among its fields.
+///
+/// A W3C `traceparent` header on the request makes the span a child of the caller's span.
+pub struct RequestSpan;
+
+impl RootSpanBuilder for RequestSpan {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        let route = request
+            .match_pattern()
+            .unwrap_or_else(|| "default".to_string());
+        let request_id = request
+            .extensions()
+            .get::<RequestId>()
+            .map(RequestId::to_string)
+            .unwrap_or_default();
+        let connection = request.connection_info();
+        let user_agent = request
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("");
+
+        let span = info_span!(
+            "HTTP request",
+            http.method = %request.method(),
+            http.route = %route,
+            http.scheme = %connection.scheme(),
+            http.host = %connection.host(),
+            http.client_ip = %client_ip::client_ip(request.request()).unwrap_or_default(),
+            http.user_agent = %user_agent,
+            http.target = %request.uri(),
+            http.status_code = Empty,
+            otel.name = %format!("{} {}", request.method(), route),
+            otel.kind = "server",
+            otel.status_code = Empty,
+            exception.message = Empty,
+            exception.details = Empty,
+            request_id = %request_id,
+            trace_id = Empty,
+            user_id = Empty,
+            outcome = Empty,
+        );
+
+        let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(&RequestHeaders(request.headers()))
+        });
+        let _ = span.set_parent(parent);
+        let trace_id = span.context().span().span_context().trace_id();
+        if trace_id != TraceId::INVALID {
+            span.record("trace_id", trace_id.to_string());
+        }
+        span
+    }
+
+    fn on_request_end<B: MessageBody>(span: Span, outcome: &Result<ServiceResponse<B>, Error>) {
+        DefaultRootSpanBuilder::on_request_end(span, outcome);
+    }
+}
+
+// Reads propagation fields from request headers.
+struct RequestHeaders<'a>(&'a actix_web::http::header::HeaderMap);
+
+impl Extractor for RequestHeaders<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Records the signed-in user on the current request's span.
+///
+/// Handlers and token validators run inside the request span, so this is the span of their request.
+pub fn record_user(user_id: i32) {
+    Span::current().record("user_id", user_id);
+}
+
+/// Middleware logging each request once it has been handled, with its status, outcome and duration.
+///
+/// The outcome is `success`, `failure` for client errors or `error` for server errors, and is also
+/// recorded on the request span.
+pub async fn log_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let started = Instant::now();
+    let res = next.call(req).await;
+    let status = match &res {
+        Ok(res) => res.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+
+    let outcome = if status.is_server_error() {
+        "error"
+    } else if status.is_client_error() {
+        "failure"
+    } else {
+        "success"
+    };
+    Span::current().record("outcome", outcome);
+    info!(
+        status = status.as_u16(),
+        duration_ms = started.elapsed().as_millis() as u64,
+        "Request finished"
+    );
+    res
+}
+
+/// Runs a database call on the blocking thread pool inside a span named after the operation.
+pub async fn db_block<F, R>(operation: &'static str, f: F) -> Result<R, BlockingError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let span = info_span!(
+        "db.query",
+        otel.name = operation,
+        db.system.name = "postgresql",
+        db.operation.name = operation,
+    );
+    web::block(move || span.in_scope(f)).await
+}
+
+/// Sends an outbound request inside a client span, passing the trace context on in the
+/// `traceparent` header.
+pub async fn send(
+    client: &reqwest::Client,
+    mut request: reqwest::Request,
+) -> reqwest::Result<reqwest::Response> {
+    let span = info_span!(
+        "http.client",
+        otel.name = %request.method(),
+        otel.kind = "client",
+        otel.status_code = Empty,
+        http.request.method = %request.method(),
+        url.full = %request.url(),
+        http.response.status_code = Empty,
+    );
+    inject_context(&span, request.headers_mut());
+
+    let response = client.execute(request).instrument(span.clone()).await;
+    match &response {
+        Ok(response) => {
+            span.record("http.response.status_code", response.status().as_u16());
+            if response.status().is_server_error() {
+                span.record("otel.status_code", "ERROR");
+            }
+        }
+        Err(_) => {
+            span.record("otel.status_code", "ERROR");
+        }
+    }
+    response
+}
+
+/// Writes the W3C trace context of `span` into outbound request headers.
+pub fn inject_context(span: &Span, headers: &mut HeaderMap) {
+    TraceContextPropagator::new().inject_context(&span.context(), &mut HeaderInjector(headers));
+}
+
+// Sets propagation fields as request headers.
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+    use prost::Message;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    // Accepts OTLP/HTTP export requests on a local port and passes the decoded spans on.
+    fn start_collector() -> (String, mpsc::Receiver<ExportTraceServiceRequest>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .unwrap();
+                sender
+                    .send(ExportTraceServiceRequest::decode(body.as_slice()).unwrap())
+                    .unwrap();
+            }
+        });
+        (endpoint, receiver)
+    }
+
+    #[test]
+    fn exports_spans_with_the_propagated_trace_context() {
+        let (endpoint, exported) = start_collector();
+        let provider = tracer_provider(&endpoint).unwrap();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        let mut headers = HeaderMap::new();
+        tracing::subscriber::with_default(subscriber, || {
+            let span = info_span!("login");
+            inject_context(&span, &mut headers);
+        });
+        provider.force_flush().unwrap();
+
+        let traceparent = headers["traceparent"].to_str().unwrap();
+        let parts: Vec<&str> = traceparent.split('-').collect();
+        assert_eq!(parts.len(), 4, "{}", traceparent);
+
+        let request = exported.recv().unwrap();
+        let span = request
+            .resource_spans
+            .iter()
+            .flat_map(|resource| &resource.scope_spans)
+            .flat_map(|scope| &scope.spans)
+            .find(|span| span.name == "login")
+            .expect("the span is exported");
+        assert_eq!(hex::encode(&span.trace_id), parts[1]);
+        assert_eq!(hex::encode(&span.span_id), parts[2]);
+    }
+}
@@ -1,18 +1,335 @@
 //! # Utility Functions Module
 //! This module provides utility functions for password handling, including hashing and verifying passwords.
-//! It leverages the `argonautica` crate to utilize the Argon2 algorithm for password security, which is
+//! It leverages the pure-Rust `argon2` crate to utilize the Argon2 algorithm for password security, which is
 //! considered one of the most secure algorithms for this purpose. The functions here are essential for
 //! user authentication processes, ensuring that passwords are stored and verified securely.
+//!
+//! The Argon2 cost parameters are configurable through `ARGON2_MEMORY_KIB`, `ARGON2_ITERATIONS` and
+//! `ARGON2_LANES`. They are recorded in every PHC hash string, so hashes created with outdated
+//! parameters can be detected with `needs_rehash` and upgraded on the user's next successful login.
+//!
+//! The secret key (pepper) mixed into every hash comes from a versioned keyring, so it can be rotated
+//! without locking users out. `SECRET_KEY` is version 0, `SECRET_KEY_V<n>` adds version `n`, and
+//! `SECRET_KEY_VERSION` selects the version used for new hashes (the highest one by default). Stored
+//! hashes are tagged with their pepper version, e.g. `$pepper-v2$argon2id$v=19$...`; untagged hashes
+//! predate the keyring and use version 0. Hashes peppered with an old version are re-peppered on login.
+//!
+//! Hashing is done through the `PasswordHasher` trait. Its Argon2 implementation produces and accepts the
+//! same keyed Argon2id PHC strings as the `argonautica` crate used previously, so existing hashes stay valid.
+//!
+//! Hashes imported from other applications (bcrypt, scrypt, PBKDF2-SHA256 and their Django and Passlib
+//! formats) are verified by the legacy hashes module and upgraded to Argon2 on the next successful login.
+//!
+//! Hashing and verification are CPU heavy, so they run on Actix's blocking thread pool rather than on the
+//! async workers. At most `MAX_CONCURRENT_HASHES` (default: one per logical core) run at once; further
+//! requests wait for a permit, so a burst of logins cannot occupy every blocking thread.
 
-// Import argonautica crate for hashing and verifying passwords.
-use argonautica::Hasher;
-use argonautica::Verifier;
-use std::env;
+// Import the argon2 crate for hashing and verifying passwords.
+use crate::errors::PasswordError;
+use crate::legacy_hashes::LegacyFormat;
+use crate::metrics;
+use crate::settings::Settings;
+use actix_web::web;
+use argon2::password_hash::{self, PasswordHash, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand_core::OsRng;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use std::time::Instant;
+use tokio::sync::Semaphore;
+use tracing::{info, info_span};
+
+// Prefix of the pepper version tag in stored hashes.
+const PEPPER_TAG_PREFIX: &str = "$pepper-v";
+
+// Password whose hash unknown-email logins are verified against.
+const DUMMY_PASSWORD: &str = "placeholder for logins with an unknown email";
+
+// Highest Argon2 cost parameters accepted in stored hashes and in the settings. Hashes above them are treated
+// as malformed, like legacy hashes above the limits of the legacy hashes module, since verifying them would
+// tie up a blocking thread for seconds or exhaust memory on every login attempt. The historical defaults
+// (4096 KiB and 192 iterations, 768 MiB of work) and the usual recommendations such as 64 MiB with three
+// iterations remain accepted.
+const MAX_ARGON2_MEMORY_KIB: u32 = 256 * 1024;
+const MAX_ARGON2_ITERATIONS: u32 = 256;
+const MAX_ARGON2_LANES: u32 = 1024;
+const MAX_ARGON2_WORK_KIB: u64 = 1024 * 1024; // Memory times iterations.
+
+/// Argon2 cost parameters used when hashing new passwords.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashParams {
+    pub memory_kib: u32, // Memory size in kibibytes (`m` in the PHC string).
+    pub iterations: u32, // Number of passes over memory (`t` in the PHC string).
+    pub lanes: u32,      // Degree of parallelism (`p` in the PHC string).
+}
+
+impl Default for HashParams {
+    /// The historical argonautica defaults: 4096 KiB, 192 iterations and one lane per logical core.
+    fn default() -> Self {
+        HashParams {
+            memory_kib: 4096,
+            iterations: 192,
+            lanes: available_parallelism(),
+        }
+    }
+}
+
+impl HashParams {
+    /// Returns the parameters set by `configure`, or the defaults if it has not been called.
+    pub fn current() -> &'static HashParams {
+        PARAMS.get_or_init(HashParams::default)
+    }
+
+    /// Extracts the parameters recorded in an Argon2id PHC hash string, such as
+    /// `$argon2id$v=19$m=4096,t=192,p=8$<salt>$<hash>`.
+    ///
+    /// Returns `None` for strings that are not version 19 Argon2id hashes.
+    pub fn from_phc(hash: &str) -> Option<Self> {
+        let mut fields = hash.split('$');
+        if fields.next() != Some("") || fields.next() != Some("argon2id") {
+            return None;
+        }
+        if fields.next() != Some("v=19") {
+            return None;
+        }
+
+        let (mut memory_kib, mut iterations, mut lanes) = (None, None, None);
+        for param in fields.next()?.split(',') {
+            let (key, value) = param.split_once('=')?;
+            let value = value.parse().ok()?;
+            match key {
+                "m" => memory_kib = Some(value),
+                "t" => iterations = Some(value),
+                "p" => lanes = Some(value),
+                _ => return None,
+            }
+        }
+
+        Some(HashParams {
+            memory_kib: memory_kib?,
+            iterations: iterations?,
+            lanes: lanes?,
+        })
+    }
+
+    /// Checks that the parameters are valid for Argon2 and within the cost limits of this module.
+    pub fn validate(&self) -> Result<(), String> {
+        Params::new(self.memory_kib, self.iterations, self.lanes, None)
+            .map_err(|e| e.to_string())?;
+        if self.memory_kib > MAX_ARGON2_MEMORY_KIB
+            || self.iterations > MAX_ARGON2_ITERATIONS
+            || self.lanes > MAX_ARGON2_LANES
+            || self.memory_kib as u64 * self.iterations as u64 > MAX_ARGON2_WORK_KIB
+        {
+            return Err(format!(
+                "at most {} KiB of memory, {} iterations, {} lanes and {} KiB of memory times iterations are supported",
+                MAX_ARGON2_MEMORY_KIB, MAX_ARGON2_ITERATIONS, MAX_ARGON2_LANES, MAX_ARGON2_WORK_KIB
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Versioned secret keys (peppers) used when hashing and verifying passwords.
+#[derive(Clone)]
+pub struct PepperKeyring {
+    current_version: u32,
+    peppers: BTreeMap<u32, String>,
+}
+
+impl PepperKeyring {
+    /// Builds a keyring from peppers keyed by version.
+    ///
+    /// New hashes use `current_version`, or the highest configured version when it is `None`.
+    pub fn new(
+        peppers: BTreeMap<u32, String>,
+        current_version: Option<u32>,
+    ) -> Result<Self, String> {
+        let current_version = match current_version {
+            Some(version) => version,
+            None => *peppers
+                .keys()
+                .next_back()
+                .ok_or("missing setting `secret_key` or `secret_key_v<n>`")?,
+        };
+        if !peppers.contains_key(&current_version) {
+            return Err(format!(
+                "invalid setting `secret_key_version`: it is {} but no key is configured for it",
+                current_version
+            ));
+        }
+
+        Ok(PepperKeyring {
+            current_version,
+            peppers,
+        })
+    }
+
+    /// Returns the keyring set by `configure`.
+    pub fn current() -> &'static PepperKeyring {
+        KEYRING.get().expect("pepper keyring must be configured")
+    }
+
+    /// Returns the pepper for the given version.
+    fn pepper(&self, version: u32) -> Result<&str, PasswordError> {
+        self.peppers
+            .get(&version)
+            .map(String::as_str)
+            .ok_or(PasswordError::UnknownPepper(version))
+    }
+}
+
+static PARAMS: OnceLock<HashParams> = OnceLock::new();
+static DUMMY_HASH: tokio::sync::OnceCell<String> = tokio::sync::OnceCell::const_new();
+static KEYRING: OnceLock<PepperKeyring> = OnceLock::new();
+static PERMITS: OnceLock<Semaphore> = OnceLock::new();
+
+/// Applies the password hashing settings. Called once at startup, before any password is hashed.
+pub fn configure(settings: &Settings) {
+    let params = settings.hash_params;
+    info!(
+        "Argon2 parameters: memory {} KiB, {} iterations, {} lanes",
+        params.memory_kib, params.iterations, params.lanes
+    );
+    info!(
+        "Pepper keyring loaded with versions {:?}, current version {}",
+        settings.keyring.peppers.keys().collect::<Vec<_>>(),
+        settings.keyring.current_version
+    );
+    info!(
+        "Allowing {} concurrent password hashing jobs",
+        settings.max_concurrent_hashes
+    );
+
+    let _ = PARAMS.set(params);
+    let _ = KEYRING.set(settings.keyring.clone());
+    let _ = PERMITS.set(Semaphore::new(settings.max_concurrent_hashes as usize));
+}
+
+// Number of logical cores, the default for Argon2 lanes and concurrent hashing jobs.
+fn available_parallelism() -> u32 {
+    std::thread::available_parallelism()
+        .map(|n| n.get() as u32)
+        .unwrap_or(1)
+}
+
+// Splits a stored hash into its pepper version and the PHC hash string.
+fn split_pepper_tag(stored: &str) -> (u32, &str) {
+    if let Some(tagged) = stored.strip_prefix(PEPPER_TAG_PREFIX) {
+        if let Some(end) = tagged.find('$') {
+            if let Ok(version) = tagged[..end].parse() {
+                return (version, &tagged[end..]);
+            }
+        }
+    }
+    (0, stored)
+}
+
+/// A password hashing algorithm whose output is stored as a PHC string.
+///
+/// Implementations are keyed with a secret key (pepper), which is not part of the output.
+pub trait PasswordHasher: Send + Sync {
+    /// Hashes `password` keyed with `secret_key` using the given cost parameters.
+    fn hash(
+        &self,
+        password: &str,
+        secret_key: &[u8],
+        params: &HashParams,
+    ) -> Result<String, PasswordError>;
+
+    /// Verifies `password` against a PHC string created with the same `secret_key`.
+    fn verify(&self, password: &str, secret_key: &[u8], phc: &str) -> Result<bool, PasswordError>;
+}
+
+/// Keyed Argon2id, compatible with hashes produced by the `argonautica` crate.
+pub struct Argon2Hasher;
+
+impl PasswordHasher for Argon2Hasher {
+    fn hash(
+        &self,
+        password: &str,
+        secret_key: &[u8],
+        params: &HashParams,
+    ) -> Result<String, PasswordError> {
+        let params = Params::new(params.memory_kib, params.iterations, params.lanes, Some(32))
+            .map_err(password_hash::Error::from)?;
+        let argon2 =
+            Argon2::new_with_secret(secret_key, Algorithm::Argon2id, Version::V0x13, params)
+                .map_err(password_hash::Error::from)?;
+
+        let salt = SaltString::generate(&mut OsRng);
+        let hash =
+            password_hash::PasswordHasher::hash_password(&argon2, password.as_bytes(), &salt)?;
+        Ok(hash.to_string())
+    }
+
+    fn verify(&self, password: &str, secret_key: &[u8], phc: &str) -> Result<bool, PasswordError> {
+        let parsed = PasswordHash::new(phc)?;
+        // The cost parameters are taken from the PHC string; only the secret key is supplied here.
+        let argon2 = Argon2::new_with_secret(
+            secret_key,
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::default(),
+        )
+        .map_err(password_hash::Error::from)?;
+
+        match password_hash::PasswordVerifier::verify_password(
+            &argon2,
+            password.as_bytes(),
+            &parsed,
+        ) {
+            Ok(()) => Ok(true),
+            Err(password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// The hasher used for all stored passwords.
+fn hasher() -> &'static dyn PasswordHasher {
+    &Argon2Hasher
+}
+
+// Limits how many hashing or verification jobs run on the blocking pool at once.
+fn hashing_permits() -> &'static Semaphore {
+    PERMITS.get_or_init(|| Semaphore::new(available_parallelism() as usize))
+}
+
+// Runs a hashing job on the blocking thread pool once a hashing permit is available, in a span and
+// recording how long the job took under the given operation and algorithm.
+async fn run_hashing_job<T, F>(
+    operation: &'static str,
+    algorithm: &'static str,
+    job: F,
+) -> Result<T, PasswordError>
+where
+    F: FnOnce() -> Result<T, PasswordError> + Send + 'static,
+    T: Send + 'static,
+{
+    let _permit = hashing_permits()
+        .acquire()
+        .await
+        .map_err(|_| PasswordError::Unavailable)?;
+
+    let span = info_span!("password_hashing", operation, algorithm);
+    web::block(move || {
+        let _entered = span.enter();
+        let started = Instant::now();
+        let result = job();
+        metrics::record_password_hashing(operation, algorithm, started.elapsed());
+        result
+    })
+    .await
+    .map_err(|_| PasswordError::Unavailable)?
+}
 
 /// Hashes a password using the Argon2 algorithm.
 ///
 /// This function takes a plaintext password as input and returns the hashed password.
-/// It retrieves the secret key from the environment variables to use in the hashing process.
+/// It peppers the hash with the current secret key from the keyring and tags the result
+/// with that key's version. It hashes with the configured `HashParams`, which are recorded
+/// in the resulting PHC string.
 /// The Argon2 algorithm is considered one of the most secure hashing algorithms for passwords.
 ///
 /// # Arguments
@@ -21,49 +338,239 @@ use std::env;
 ///
 /// # Returns
 ///
-/// This function returns a `Result` which is Ok containing the hashed password as a `String`
-/// if the operation is successful, or an `argonautica::Error` if it fails.
+/// This function returns a `Result` which is Ok containing the tagged hashed password as a `String`
+/// if the operation is successful, or a `PasswordError` if it fails.
+pub async fn hash_password(password: &str) -> Result<String, PasswordError> {
+    // Retrieve the current secret key from the keyring.
+    let keyring = PepperKeyring::current();
+    let secret_key = keyring.pepper(keyring.current_version)?;
 
-pub async fn hash_password(password: &str) -> Result<String, argonautica::Error> {
-    // Retrieve the secret key from environment variable.
-    let secret_key = env::var("SECRET_KEY").expect("SECRET_KEY must be set");
+    // Hash with the configured parameters on the blocking pool.
+    let password = password.to_owned();
+    let hash = run_hashing_job("hash", "argon2", move || {
+        hasher().hash(&password, secret_key.as_bytes(), HashParams::current())
+    })
+    .await?;
 
-    // Initialize the hasher with default parameters.
-    let mut hasher = Hasher::default();
-
-    // Set the password, secret key, and perform the hashing.
-    hasher
-        .with_password(password)
-        .with_secret_key(secret_key)
-        .hash() // Perform the hash operation and return the result.
+    // Tag the hash with the pepper version used.
+    Ok(format!(
+        "{}{}{}",
+        PEPPER_TAG_PREFIX, keyring.current_version, hash
+    ))
 }
 
 /// Verifies a password against a hash.
 ///
 /// This function is used to verify if a given plaintext password matches the hashed version.
-/// It is primarily used during the login process to authenticate users.
+/// It is primarily used during the login process to authenticate users. The secret key is
+/// chosen from the keyring by the hash's pepper version tag. Imported legacy hashes are
+/// recognised by their algorithm prefix and verified without a secret key.
 ///
 /// # Arguments
 ///
 /// * `password` - A string slice that holds the plaintext password to verify.
-/// * `hash` - A string slice that holds the hashed password to compare against.
+/// * `hash` - A string slice that holds the stored hash to compare against.
 ///
 /// # Returns
 ///
 /// Returns a `Result` which is Ok containing a boolean value `true` if the password matches the hash,
-/// or `false` otherwise. It may also return an `argonautica::Error` if the verification process fails.
+/// or `false` otherwise. It may also return a `PasswordError` if the verification process fails.
+pub async fn verify_password(password: &str, hash: &str) -> Result<bool, PasswordError> {
+    let password = password.to_owned();
+    let hash = hash.to_owned();
+
+    // Imported hashes from other applications are verified by their own algorithm.
+    if let Some(format) = LegacyFormat::detect(&hash) {
+        return run_hashing_job("verify", "legacy", move || format.verify(&password, &hash)).await;
+    }
+
+    // Retrieve the secret key the hash was created with.
+    let (pepper_version, untagged) = split_pepper_tag(&hash);
+    let secret_key = PepperKeyring::current().pepper(pepper_version)?;
+
+    // Hashes over the cost limits are rejected before any work is done.
+    if HashParams::from_phc(untagged).is_some_and(|params| params.validate().is_err()) {
+        return Err(PasswordError::MalformedHash("argon2"));
+    }
+
+    run_hashing_job("verify", "argon2", move || {
+        let (_, hash) = split_pepper_tag(&hash);
+        hasher().verify(&password, secret_key.as_bytes(), hash)
+    })
+    .await
+}
+
+/// Verifies a password against the hash of a placeholder password and discards the result.
+///
+/// Logins with an unknown email call this instead of `verify_password`, so that they take as long as
+/// logins with a wrong password and the response time does not reveal whether an account exists. The
+/// placeholder is hashed once, with the current pepper and Argon2 parameters, like the hashes of users who
+/// logged in recently.
+///
+/// # Arguments
+///
+/// * `password` - A string slice that holds the plaintext password from the login request.
+pub async fn verify_dummy_password(password: &str) {
+    let Ok(hash) = DUMMY_HASH
+        .get_or_try_init(|| hash_password(DUMMY_PASSWORD))
+        .await
+    else {
+        return;
+    };
+    let _ = verify_password(password, hash).await;
+}
+
+/// Checks whether a stored hash should be recreated with the current settings.
+///
+/// This is used after a successful login to transparently upgrade the stored hash. A hash is
+/// outdated when it was peppered with a key other than the current one, or created with Argon2
+/// parameters other than the configured ones. Hashes that cannot be parsed as Argon2id PHC
+/// strings, including imported legacy hashes, are always considered outdated.
+///
+/// # Arguments
+///
+/// * `hash` - A string slice that holds the stored hash.
+///
+/// # Returns
+///
+/// Returns `true` if the password should be rehashed with the current settings.
+pub fn needs_rehash(hash: &str) -> bool {
+    let (pepper_version, hash) = split_pepper_tag(hash);
+    pepper_version != PepperKeyring::current().current_version
+        || HashParams::from_phc(hash).as_ref() != Some(HashParams::current())
+}
+
+/// Checks whether a pre-hashed password can be stored as-is, for example during a bulk import.
+///
+/// Accepted are Argon2id hashes in this service's format whose pepper version is configured and whose
+/// parameters are within the cost limits, and imported legacy hashes that `verify_password` can verify:
+/// well-formed and within its cost limits.
+pub fn is_supported_hash(hash: &str) -> bool {
+    if let Some(format) = LegacyFormat::detect(hash) {
+        return format.validate(hash).is_ok();
+    }
+
+    let (pepper_version, hash) = split_pepper_tag(hash);
+    PepperKeyring::current().pepper(pepper_version).is_ok()
+        && HashParams::from_phc(hash).is_some_and(|params| params.validate().is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hashes produced by the reference C implementation bundled with argonautica 0.2, through the same
+    // `argon2_ctx` call its `Hasher` makes: keyed Argon2id, version 19, 32 byte salt and 32 byte output.
+    const ARGONAUTICA_HASHES: &[(&str, &str, &str)] = &[
+        (
+            "correct horse battery staple",
+            "legacy-secret-key",
+            "$argon2id$v=19$m=256,t=3,p=2$AwoRGB8mLTQ7QklQV15lbHN6gYiPlp2kq7K5wMfO1dw$o9fSDnQ0PUKDlG34ZDvq45Bf2kkvGORvzNVcS/T5QdU",
+        ),
+        (
+            "P@ssw0rd!",
+            "another secret",
+            "$argon2id$v=19$m=4096,t=2,p=4$//r18Ovm4dzX0s3Iw765tK+qpaCblpGMh4J9eHNuaWQ$QbDwIKfNbVUGVCUUl2HSwqO//N9e9PgL7GgeE41QVvE",
+        ),
+    ];
+
+    const TEST_PARAMS: HashParams = HashParams {
+        memory_kib: 64,
+        iterations: 1,
+        lanes: 2,
+    };
+
+    #[test]
+    fn verifies_argonautica_hashes() {
+        for (password, secret_key, hash) in ARGONAUTICA_HASHES {
+            assert!(Argon2Hasher
+                .verify(password, secret_key.as_bytes(), hash)
+                .unwrap());
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_password_or_secret_key_for_argonautica_hashes() {
+        for (password, secret_key, hash) in ARGONAUTICA_HASHES {
+            assert!(!Argon2Hasher
+                .verify("wrong password", secret_key.as_bytes(), hash)
+                .unwrap());
+            assert!(!Argon2Hasher
+                .verify(password, b"wrong secret key", hash)
+                .unwrap());
+        }
+    }
+
+    #[test]
+    fn verifies_pepper_tagged_argonautica_hashes() {
+        let (password, secret_key, hash) = ARGONAUTICA_HASHES[0];
+        let tagged = format!("{}3{}", PEPPER_TAG_PREFIX, hash);
+
+        let (version, untagged) = split_pepper_tag(&tagged);
+        assert_eq!((version, untagged), (3, hash));
+        assert!(Argon2Hasher
+            .verify(password, secret_key.as_bytes(), untagged)
+            .unwrap());
+        assert_eq!(split_pepper_tag(hash), (0, hash));
+    }
+
+    #[test]
+    fn new_hashes_use_argonautica_format() {
+        let hash = Argon2Hasher
+            .hash("hunter42", b"secret", &TEST_PARAMS)
+            .unwrap();
+
+        assert!(hash.starts_with("$argon2id$v=19$m=64,t=1,p=2$"));
+        assert_eq!(HashParams::from_phc(&hash), Some(TEST_PARAMS));
+        assert!(Argon2Hasher.verify("hunter42", b"secret", &hash).unwrap());
+        assert!(!Argon2Hasher
+            .verify("hunter42", b"other secret", &hash)
+            .unwrap());
+    }
 
-pub fn verify_password(password: &str, hash: &str) -> Result<bool, argonautica::Error> {
-    // Retrieve the secret key from environment variable.
-    let secret_key = env::var("SECRET_KEY").expect("SECRET_KEY must be set");
+    #[test]
+    fn limits_argon2_costs() {
+        for (_, _, hash) in ARGONAUTICA_HASHES {
+            assert_eq!(HashParams::from_phc(hash).unwrap().validate(), Ok(()));
+        }
+        assert_eq!(HashParams::default().validate(), Ok(()));
+        let recommended = HashParams {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            lanes: 4,
+        };
+        assert_eq!(recommended.validate(), Ok(()));
 
-    // Initialize the verifier with default parameters.
-    let mut verifier = Verifier::default();
+        for (memory_kib, iterations, lanes) in [
+            (4 * 1024 * 1024, 1, 1),
+            (4096, 100_000, 1),
+            (4096, 1, 100_000),
+            (256 * 1024, 8, 1),
+            (1, 1, 1),
+        ] {
+            let params = HashParams {
+                memory_kib,
+                iterations,
+                lanes,
+            };
+            assert!(
+                params.validate().is_err(),
+                "{:?} should be rejected",
+                params
+            );
+        }
+    }
 
-    // Set the hash, password, secret key, and perform the verification.
-    verifier
-        .with_hash(hash)
-        .with_password(password)
-        .with_secret_key(secret_key)
-        .verify() // Perform the verification and return the result.
+    #[test]
+    fn reads_params_from_argonautica_hashes() {
+        let params = HashParams::from_phc(ARGONAUTICA_HASHES[1].2).unwrap();
+        assert_eq!(
+            params,
+            HashParams {
+                memory_kib: 4096,
+                iterations: 2,
+                lanes: 4,
+            }
+        );
+    }
 }
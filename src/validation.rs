@@ -0,0 +1,205 @@
+//! # Validation Module
+//!
+//! This module holds the field rules shared by the request DTOs, which declare them with
+//! `#[derive(Validate)]`, and the conversion of validation failures into a per-field error map. Request
+//! bodies that fail validation, or that cannot be deserialized because a field is missing, unknown or of
+//! the wrong type, are answered with `422 Unprocessable Entity` and a body like
+//! `{"errors": {"email": ["email is not a valid address"]}}`.
+
+use crate::email;
+use crate::errors::ServiceError;
+use actix_web::error::JsonPayloadError;
+use actix_web::HttpRequest;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use tracing::debug;
+use validator::{ValidationError, ValidationErrors, ValidationErrorsKind};
+
+/// Field name used for errors that do not belong to a single field.
+pub const BODY_FIELD: &str = "body";
+
+/// Validation failures, keyed by field name.
+pub type FieldErrors = BTreeMap<String, Vec<String>>;
+
+/// Accepts personal names: letters, marks, spaces, apostrophes, hyphens and periods.
+pub fn validate_name(name: &str) -> Result<(), ValidationError> {
+    let allowed = name.chars().all(|c| {
+        c.is_alphabetic() || c == ' ' || c == '\'' || c == '-' || c == '.' || is_combining_mark(c)
+    });
+    if !allowed || name.trim().is_empty() {
+        return Err(ValidationError::new("name").with_message(Cow::from(
+            "may only contain letters, spaces, apostrophes, hyphens and periods",
+        )));
+    }
+    Ok(())
+}
+
+/// Accepts email addresses that the email module can normalize.
+pub fn validate_email(address: &str) -> Result<(), ValidationError> {
+    email::normalize(address)
+        .map(|_| ())
+        .map_err(|e| ValidationError::new("email").with_message(Cow::from(e)))
+}
+
+// Combining marks used in names written with decomposed characters.
+fn is_combining_mark(c: char) -> bool {
+    matches!(c, '\u{0300}'..='\u{036F}')
+}
+
+/// Flattens nested validation errors into messages keyed by field path, such as `address.city`.
+pub fn field_errors(errors: &ValidationErrors) -> FieldErrors {
+    let mut fields = FieldErrors::new();
+    collect_errors(errors, "", &mut fields);
+    fields
+}
+
+fn collect_errors(errors: &ValidationErrors, prefix: &str, fields: &mut FieldErrors) {
+    for (field, kind) in errors.errors() {
+        let path = if prefix.is_empty() {
+            field.to_string()
+        } else {
+            format!("{}.{}", prefix, field)
+        };
+        match kind {
+            ValidationErrorsKind::Field(errors) => fields
+                .entry(path)
+                .or_default()
+                .extend(errors.iter().map(describe)),
+            ValidationErrorsKind::Struct(errors) => collect_errors(errors, &path, fields),
+            ValidationErrorsKind::List(items) => {
+                for (index, errors) in items {
+                    collect_errors(errors, &format!("{}[{}]", path, index), fields);
+                }
+            }
+        }
+    }
+}
+
+// Describes a single failed rule, preferring the rule's own message.
+fn describe(error: &ValidationError) -> String {
+    if let Some(message) = &error.message {
+        return message.to_string();
+    }
+    match error.code.as_ref() {
+        "length" => match (error.params.get("min"), error.params.get("max")) {
+            (Some(min), Some(max)) => {
+                format!("must be between {} and {} characters long", min, max)
+            }
+            (Some(min), None) => format!("must be at least {} characters long", min),
+            (None, Some(max)) => format!("must be at most {} characters long", max),
+            (None, None) => "has an invalid length".to_string(),
+        },
+        code => format!("failed the {} check", code),
+    }
+}
+
+/// Converts rejected JSON request bodies into errors.
+///
+/// Bodies that are valid JSON but do not match the DTO, for example because of a missing or unknown
+/// field, are reported like validation failures; other payload errors are bad requests.
+pub fn json_error_handler(err: JsonPayloadError, req: &HttpRequest) -> actix_web::Error {
+    debug!("Rejected JSON body for request {:?}: {}", req.path(), err);
+
+    let error = match &err {
+        JsonPayloadError::Deserialize(e) => deserialize_error(e),
+        _ => ServiceError::BadRequest(format!("Invalid request body: {}", err)),
+    };
+    error.into()
+}
+
+/// Converts a failure to deserialize a JSON request body into an error.
+pub fn deserialize_error(e: &serde_json::Error) -> ServiceError {
+    if !e.is_data() {
+        return ServiceError::BadRequest(format!("Invalid request body: {}", e));
+    }
+
+    let message = e.to_string();
+    let field = field_from_serde_message(&message).unwrap_or(BODY_FIELD);
+    let mut fields = FieldErrors::new();
+    fields.insert(
+        field.to_string(),
+        vec![strip_position(&message).to_string()],
+    );
+    ServiceError::Validation(fields)
+}
+
+// Extracts the field name from serde's "unknown field `x`" and "missing field `x`" messages.
+fn field_from_serde_message(message: &str) -> Option<&str> {
+    let rest = message
+        .strip_prefix("unknown field `")
+        .or_else(|| message.strip_prefix("missing field `"))?;
+    rest.split('`').next()
+}
+
+// Removes the " at line 1 column 2" suffix serde_json appends to its messages.
+fn strip_position(message: &str) -> &str {
+    message
+        .rfind(" at line ")
+        .map_or(message, |index| &message[..index])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use validator::Validate;
+
+    #[derive(Debug, Deserialize, Validate)]
+    #[serde(deny_unknown_fields)]
+    struct Person {
+        #[validate(length(min = 1, max = 5), custom(function = "validate_name"))]
+        name: String,
+        #[validate(custom(function = "validate_email"))]
+        email: String,
+    }
+
+    #[test]
+    fn reports_errors_per_field() {
+        let person = Person {
+            name: "Robert1".to_string(),
+            email: "robert".to_string(),
+        };
+        let fields = field_errors(&person.validate().unwrap_err());
+
+        assert_eq!(
+            fields["name"],
+            vec![
+                "must be between 1 and 5 characters long".to_string(),
+                "may only contain letters, spaces, apostrophes, hyphens and periods".to_string(),
+            ]
+        );
+        assert_eq!(
+            fields["email"],
+            vec!["email is not a valid address".to_string()]
+        );
+    }
+
+    #[test]
+    fn accepts_international_names() {
+        for name in ["Zoë", "O'Brien", "Jean-Luc", "J. R.", "José", "李"] {
+            assert!(validate_name(name).is_ok(), "{:?} should be accepted", name);
+        }
+        for name in ["", " ", "Bob<script>", "R2D2"] {
+            assert!(
+                validate_name(name).is_err(),
+                "{:?} should be rejected",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn names_the_field_of_deserialization_errors() {
+        let unknown =
+            serde_json::from_str::<Person>(r#"{"name": "A", "email": "a@b.c", "admin": true}"#)
+                .unwrap_err()
+                .to_string();
+        assert_eq!(field_from_serde_message(&unknown), Some("admin"));
+
+        let missing = serde_json::from_str::<Person>(r#"{"name": "A"}"#)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(field_from_serde_message(&missing), Some("email"));
+        assert_eq!(strip_position(&missing), "missing field `email`");
+    }
+}